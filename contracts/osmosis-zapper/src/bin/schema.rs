@@ -1,10 +1,11 @@
 use cosmwasm_schema::write_api;
-use osmosis_zapper::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
+use osmosis_zapper::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, SudoMsg};
 
 fn main() {
     write_api! {
         instantiate: InstantiateMsg,
         execute: ExecuteMsg,
-        query: QueryMsg
+        query: QueryMsg,
+        sudo: SudoMsg
     }
 }
@@ -1,24 +1,43 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
-use cw20::{Cw20Coin, Cw20ReceiveMsg};
-use zapper::{
-    asset::{get_current_asset_available, Asset},
-    error::ZapperError,
-};
+use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw_utils::{one_coin, Expiration};
+use zapper::{asset::Asset, error::ZapperError, swap::Route};
 
 use crate::{
     error::{ContractError, ContractResult},
-    msg::{Cw20HookMsg, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg},
-    reply::{reply_create_position, reply_withdraw_position},
-    state::{ProtocolFee, OWNER, PROTOCOL_FEE, SNAP_BALANCES},
-    zap::{create_position, zap_in_liquidity, zap_out_liquidity},
+    helper::{create_ibc_transfer_msg, create_osmosis_swap_msg, validate_target_paths},
+    msg::{
+        ConverterHookMsg, Cw20HookMsg, EffectiveFeeResponse, ExecuteMsg, IbcLifecycleComplete,
+        InstantiateMsg, MigrateMsg, QueryMsg, SudoMsg,
+    },
+    reply::{
+        reply_add_to_position, reply_create_position, reply_ibc_transfer, reply_ibc_zap_in,
+        reply_join_pool, reply_migrate_position, reply_withdraw_position,
+    },
+    simulate::{simulate_zap_in, simulate_zap_out},
+    state::{
+        clear_snapshot_balances, get_available_balance, load_snapshot_balances, next_nonce,
+        protocol_fee_percent, snapshot_balances, Cw20Converter, FeeDirection, FeeReceiver,
+        IbcReturn, PendingIbcTransfer, PendingIbcZapIn, PendingOwner, ProtocolFee, ACCRUED_FEES,
+        CW20_CONVERTERS, IBC_TRANSFERS, MAX_REFERRAL_BPS, OWNER, PENDING_IBC_TRANSFERS,
+        PENDING_IBC_ZAP_INS, PENDING_OWNER, POOL_FEES, POSITION_OWNERS, PROTOCOL_FEE,
+        REFERRAL_FEES, SNAP_BALANCES, TREASURY_DENOM,
+    },
+    zap::{
+        add_to_position, auto_compound, create_position, harvest_rewards, join_gamm_pool,
+        migrate_position, zap_in_gamm_pool, zap_in_liquidity, zap_in_liquidity_multi,
+        zap_into_position, zap_out_gamm_pool, zap_out_liquidity, zap_out_to,
+    },
 };
 
 use cosmwasm_std::{
-    from_json, to_json_binary, Addr, Binary, CosmosMsg, Decimal, Deps, DepsMut, Env, MessageInfo,
-    Order, Reply, Response, StdResult,
+    coin, from_json, to_json_binary, wasm_execute, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal,
+    Deps, DepsMut, Env, MessageInfo, Order, Reply, Response, StdError, StdResult, SubMsg, Uint128,
+    WasmMsg,
 };
 use cw2::set_contract_version;
+use osmosis_std::types::osmosis::concentratedliquidity::v1beta1::ConcentratedliquidityQuerier;
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:zapper";
@@ -26,6 +45,24 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const CREATE_POSITION_ID: u64 = 1;
 pub const WITHDRAW_POSITION_ID: u64 = 2;
+pub const JOIN_POOL_ID: u64 = 3;
+pub const EXIT_POOL_ID: u64 = 4;
+pub const ADD_TO_POSITION_ID: u64 = 5;
+pub const MIGRATE_POSITION_ID: u64 = 6;
+pub const HARVEST_REWARDS_ID: u64 = 7;
+pub const IBC_ZAP_IN_ID: u64 = 8;
+pub const IBC_TRANSFER_ID: u64 = 9;
+
+// the low byte of a reply id is the reply kind, the rest is the operation nonce
+const REPLY_KIND_BITS: u64 = 8;
+
+pub fn encode_reply_id(kind: u64, nonce: u64) -> u64 {
+    (nonce << REPLY_KIND_BITS) | kind
+}
+
+pub fn decode_reply_id(id: u64) -> (u64, u64) {
+    (id & ((1 << REPLY_KIND_BITS) - 1), id >> REPLY_KIND_BITS)
+}
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
@@ -48,7 +85,11 @@ pub fn execute(
 ) -> ContractResult<Response> {
     match msg {
         ExecuteMsg::Receive(msg) => receive_cw20(deps, env, info, msg),
-        ExecuteMsg::ChangeOwner { new_owner } => execute_change_owner(deps, info, new_owner),
+        ExecuteMsg::ProposeNewOwner { new_owner, expiry } => {
+            execute_propose_new_owner(deps, env, info, new_owner, expiry)
+        }
+        ExecuteMsg::AcceptOwnership {} => execute_accept_ownership(deps, env, info),
+        ExecuteMsg::CancelOwnershipProposal {} => execute_cancel_ownership_proposal(deps, info),
         ExecuteMsg::ZapInLiquidity {
             pool_id,
             token_0,
@@ -58,6 +99,9 @@ pub fn execute(
             token_min_amount_0,
             token_min_amount_1,
             routes,
+            optimal_swap,
+            deadline,
+            referral,
         } => zap_in_liquidity(
             deps,
             env,
@@ -69,8 +113,38 @@ pub fn execute(
             upper_tick,
             token_min_amount_0,
             token_min_amount_1,
-            None,
             routes,
+            optimal_swap,
+            deadline,
+            referral,
+        ),
+        ExecuteMsg::ZapInLiquidityMulti {
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            cw20_asset,
+            routes,
+            deadline,
+            referral,
+        } => zap_in_liquidity_multi(
+            deps,
+            env,
+            info,
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            cw20_asset,
+            routes,
+            deadline,
+            referral,
         ),
         ExecuteMsg::CreatePosition {
             pool_id,
@@ -80,6 +154,7 @@ pub fn execute(
             upper_tick,
             token_min_amount_0,
             token_min_amount_1,
+            nonce,
         } => create_position(
             deps,
             env,
@@ -91,26 +166,248 @@ pub fn execute(
             upper_tick,
             token_min_amount_0,
             token_min_amount_1,
+            nonce,
+        ),
+        ExecuteMsg::ZapIntoPosition {
+            position_id,
+            routes,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+            referral,
+        } => zap_into_position(
+            deps,
+            env,
+            info,
+            position_id,
+            routes,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+            referral,
         ),
+        ExecuteMsg::AddToPosition {
+            position_id,
+            token_0,
+            token_1,
+            token_min_amount_0,
+            token_min_amount_1,
+            nonce,
+        } => add_to_position(
+            deps,
+            env,
+            info,
+            position_id,
+            token_0,
+            token_1,
+            token_min_amount_0,
+            token_min_amount_1,
+            nonce,
+        ),
+        ExecuteMsg::MigratePosition {
+            position_id,
+            new_lower_tick,
+            new_upper_tick,
+            paths,
+            max_slippage,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+        } => migrate_position(
+            deps,
+            env,
+            info,
+            position_id,
+            new_lower_tick,
+            new_upper_tick,
+            paths,
+            max_slippage,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+        ),
+        ExecuteMsg::AutoCompound {
+            position_id,
+            routes,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+        } => auto_compound(
+            deps,
+            env,
+            info,
+            position_id,
+            routes,
+            token_min_amount_0,
+            token_min_amount_1,
+            deadline,
+        ),
+        ExecuteMsg::HarvestRewards {
+            position_id,
+            target_denom,
+            paths,
+            minimum_receive,
+            deadline,
+            ibc_return,
+        } => harvest_rewards(
+            deps,
+            env,
+            info,
+            position_id,
+            target_denom,
+            paths,
+            minimum_receive,
+            deadline,
+            ibc_return,
+        ),
+        ExecuteMsg::ZapInGammPool {
+            pool_id,
+            routes,
+            share_out_min_amount,
+            deadline,
+            referral,
+        } => zap_in_gamm_pool(
+            deps,
+            env,
+            info,
+            pool_id,
+            routes,
+            share_out_min_amount,
+            deadline,
+            referral,
+        ),
+        ExecuteMsg::JoinGammPool {
+            pool_id,
+            share_out_min_amount,
+            nonce,
+        } => join_gamm_pool(deps, env, info, pool_id, share_out_min_amount, nonce),
         ExecuteMsg::ZapOutLiquidity {
             position_id,
             routes,
-        } => zap_out_liquidity(deps, env, info, position_id, routes),
-        ExecuteMsg::TransferFundsBack { receiver } => {
-            execute_transfer_funds_back(deps, env, info, receiver)
+            liquidity_amount,
+            percent,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        } => zap_out_liquidity(
+            deps,
+            env,
+            info,
+            position_id,
+            routes,
+            liquidity_amount,
+            percent,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        ),
+        ExecuteMsg::ZapOutGammPool {
+            routes,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        } => zap_out_gamm_pool(
+            deps,
+            env,
+            info,
+            routes,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        ),
+        ExecuteMsg::ZapOutTo {
+            position_id,
+            target_denom,
+            paths,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        } => zap_out_to(
+            deps,
+            env,
+            info,
+            position_id,
+            target_denom,
+            paths,
+            minimum_receive,
+            deadline,
+            ibc_return,
+            referral,
+        ),
+        ExecuteMsg::TransferFundsBack {
+            receiver,
+            nonce,
+            minimum_receive,
+            ibc_return,
+        } => execute_transfer_funds_back(
+            deps,
+            env,
+            info,
+            receiver,
+            nonce,
+            minimum_receive,
+            ibc_return,
+        ),
+        ExecuteMsg::ZapInConverted {
+            receiver,
+            nonce,
+            native_denom,
+            msg,
+        } => execute_zap_in_converted(deps, env, info, receiver, nonce, native_denom, msg),
+        ExecuteMsg::IbcHookZapIn {
+            receiver,
+            recovery_address,
+            msg,
+        } => execute_ibc_hook_zap_in(deps, env, info, receiver, recovery_address, msg),
+        ExecuteMsg::ZapInFor { receiver, msg } => {
+            execute_zap_in_for(deps, env, info, receiver, msg)
+        }
+        ExecuteMsg::RegisterCw20Converter {
+            cw20_address,
+            converter,
+        } => execute_register_cw20_converter(deps, info, cw20_address, converter),
+        ExecuteMsg::RegisterPositionOwner { position_id, owner } => {
+            execute_register_position_owner(deps, env, info, position_id, owner)
+        }
+        ExecuteMsg::SetPoolFee {
+            pool_id,
+            direction,
+            percent,
+        } => execute_set_pool_fee(deps, info, pool_id, direction, percent),
+        ExecuteMsg::RemovePoolFee { pool_id, direction } => {
+            execute_remove_pool_fee(deps, info, pool_id, direction)
+        }
+        ExecuteMsg::SetMaxReferralBps { max_bps } => {
+            execute_set_max_referral_bps(deps, info, max_bps)
         }
         ExecuteMsg::RegisterProtocolFee {
             percent,
             fee_receiver,
-        } => execute_register_protocol_fee(deps, info, percent, fee_receiver),
+            fee_receivers,
+        } => execute_register_protocol_fee(deps, info, percent, fee_receiver, fee_receivers),
+        ExecuteMsg::ClaimFees { denoms } => execute_claim_fees(deps, info, denoms),
+        ExecuteMsg::ConvertFees {
+            routes,
+            minimum_receive,
+        } => execute_convert_fees(deps, env, info, routes, minimum_receive),
+        ExecuteMsg::AccrueConvertedFees {
+            nonce,
+            minimum_receive,
+        } => execute_accrue_converted_fees(deps, env, info, nonce, minimum_receive),
+        ExecuteMsg::SetTreasuryDenom { denom } => execute_set_treasury_denom(deps, info, denom),
         ExecuteMsg::Withdraw { assets, recipient } => {
-            execute_withdraw(deps, info, assets, recipient)
+            execute_withdraw(deps, env, info, assets, recipient)
         }
     }
 }
 
 //////////////////////////
-/// RECEIVE ENTRYPOINT ///
+//  RECEIVE ENTRYPOINT  //
 //////////////////////////
 
 // Receive is the main entry point for the contract to
@@ -121,12 +418,149 @@ fn receive_cw20(
     info: MessageInfo,
     cw20_msg: Cw20ReceiveMsg,
 ) -> ContractResult<Response> {
-    let sent_asset = Asset::Cw20(Cw20Coin {
-        address: info.sender.to_string(),
-        amount: cw20_msg.amount,
-    });
+    // Osmosis pools only accept bank denoms, the cw20 is converted before the zap
+    let converter = CW20_CONVERTERS
+        .may_load(deps.storage, &info.sender)?
+        .ok_or(ContractError::AssetNotNative)?;
+    let receiver = deps.api.addr_validate(&cw20_msg.sender)?;
+    let hook_msg: Cw20HookMsg = from_json(&cw20_msg.msg)?;
+
+    let nonce = next_nonce(deps.storage)?;
+    snapshot_balances(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env,
+        nonce,
+        &converter.native_denom,
+    )?;
+
+    let convert_msg = WasmMsg::Execute {
+        contract_addr: info.sender.to_string(),
+        msg: to_json_binary(&Cw20ExecuteMsg::Send {
+            contract: converter.contract.to_string(),
+            amount: cw20_msg.amount,
+            msg: to_json_binary(&ConverterHookMsg::Convert {})?,
+        })?,
+        funds: vec![],
+    };
+    let zap_in_msg = wasm_execute(
+        env.contract.address.to_string(),
+        &ExecuteMsg::ZapInConverted {
+            receiver,
+            nonce,
+            native_denom: converter.native_denom,
+            msg: hook_msg,
+        },
+        vec![],
+    )?;
+
+    Ok(Response::new()
+        .add_message(convert_msg)
+        .add_message(zap_in_msg))
+}
+
+fn execute_zap_in_converted(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    receiver: Addr,
+    nonce: u64,
+    native_denom: String,
+    msg: Cw20HookMsg,
+) -> ContractResult<Response> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let balance_before = SNAP_BALANCES.load(deps.storage, (nonce, &native_denom))?;
+    let converted = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &native_denom,
+    )?
+    .amount()
+    .checked_sub(balance_before)?;
+    clear_snapshot_balances(deps.storage, nonce)?;
+
+    // the converted coin is zapped in with the forwarded funds as if the receiver had sent them
+    let mut funds = info.funds;
+    match funds.iter_mut().find(|fund| fund.denom == native_denom) {
+        Some(fund) => fund.amount = fund.amount.checked_add(converted)?,
+        None => funds.push(coin(converted.u128(), native_denom)),
+    }
+    let info = MessageInfo {
+        sender: receiver,
+        funds,
+    };
+    zap_in_hook(deps, env, info, msg)
+}
+
+// Entry point of ibc-hooks memos, the zap runs as a submessage so that the received
+// funds can be sent to the recovery address when it fails
+fn execute_ibc_hook_zap_in(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    receiver: String,
+    recovery_address: String,
+    msg: Cw20HookMsg,
+) -> ContractResult<Response> {
+    let receiver = deps.api.addr_validate(&receiver)?;
+    let recovery_address = deps.api.addr_validate(&recovery_address)?;
+    let funds = vec![one_coin(&info)?];
+
+    let nonce = next_nonce(deps.storage)?;
+    PENDING_IBC_ZAP_INS.save(
+        deps.storage,
+        nonce,
+        &PendingIbcZapIn {
+            recovery_address,
+            funds: funds.clone(),
+        },
+    )?;
+
+    let zap_in_msg = wasm_execute(
+        env.contract.address.to_string(),
+        &ExecuteMsg::ZapInFor { receiver, msg },
+        funds,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "ibc_hook_zap_in")
+        .add_submessage(SubMsg::reply_always(
+            zap_in_msg,
+            encode_reply_id(IBC_ZAP_IN_ID, nonce),
+        )))
+}
+
+fn execute_zap_in_for(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    receiver: Addr,
+    msg: Cw20HookMsg,
+) -> ContractResult<Response> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized {});
+    }
 
-    match from_json(&cw20_msg.msg)? {
+    let info = MessageInfo {
+        sender: receiver,
+        funds: info.funds,
+    };
+    zap_in_hook(deps, env, info, msg)
+}
+
+fn zap_in_hook(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: Cw20HookMsg,
+) -> ContractResult<Response> {
+    match msg {
         Cw20HookMsg::ZapInLiquidity {
             pool_id,
             token_0,
@@ -136,6 +570,9 @@ fn receive_cw20(
             token_min_amount_0,
             token_min_amount_1,
             routes,
+            optimal_swap,
+            deadline,
+            referral,
         } => zap_in_liquidity(
             deps,
             env,
@@ -147,18 +584,162 @@ fn receive_cw20(
             upper_tick,
             token_min_amount_0,
             token_min_amount_1,
-            Some(sent_asset),
             routes,
+            optimal_swap,
+            deadline,
+            referral,
+        ),
+        Cw20HookMsg::ZapInLiquidityMulti {
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            routes,
+            deadline,
+            referral,
+        } => zap_in_liquidity_multi(
+            deps,
+            env,
+            info,
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            None,
+            routes,
+            deadline,
+            referral,
         ),
     }
 }
 
-fn execute_change_owner(
+fn execute_register_cw20_converter(
+    deps: DepsMut,
+    info: MessageInfo,
+    cw20_address: Addr,
+    converter: Option<Cw20Converter>,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    let cw20_address = deps.api.addr_validate(cw20_address.as_str())?;
+
+    match converter {
+        Some(converter) => {
+            let converter = Cw20Converter {
+                contract: deps.api.addr_validate(converter.contract.as_str())?,
+                native_denom: converter.native_denom,
+            };
+            CW20_CONVERTERS.save(deps.storage, &cw20_address, &converter)?;
+        }
+        None => CW20_CONVERTERS.remove(deps.storage, &cw20_address),
+    }
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "register_cw20_converter"),
+        ("cw20_address", cw20_address.as_str()),
+    ]))
+}
+
+fn execute_register_position_owner(
     deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    owner: Addr,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    let owner = deps.api.addr_validate(owner.as_str())?;
+
+    // only positions the contract holds without a depositor can be registered
+    let position = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .and_then(|position_detail| position_detail.position)
+        .ok_or_else(|| StdError::not_found("position"))?;
+    if position.address != env.contract.address.as_str()
+        || POSITION_OWNERS.has(deps.storage, position_id)
+    {
+        return Err(ContractError::PositionNotHeld(position_id));
+    }
+
+    POSITION_OWNERS.save(deps.storage, position_id, &owner)?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "register_position_owner"),
+        ("position_id", &position_id.to_string()),
+        ("owner", owner.as_str()),
+    ]))
+}
+
+fn execute_propose_new_owner(
+    deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     new_owner: Addr,
+    expiry: Option<Expiration>,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    let new_owner = deps.api.addr_validate(new_owner.as_str())?;
+    if expiry.is_some_and(|expiry| expiry.is_expired(&env.block)) {
+        return Err(ContractError::OwnershipProposalExpired);
+    }
+
+    PENDING_OWNER.save(
+        deps.storage,
+        &PendingOwner {
+            new_owner: new_owner.clone(),
+            expiry,
+        },
+    )?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "propose_new_owner"),
+        ("new_owner", new_owner.as_str()),
+    ]))
+}
+
+fn execute_accept_ownership(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
 ) -> ContractResult<Response> {
-    Ok(OWNER.execute_update_admin(deps, info, Some(new_owner))?)
+    let pending_owner = PENDING_OWNER
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingOwner)?;
+
+    if info.sender != pending_owner.new_owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if pending_owner
+        .expiry
+        .is_some_and(|expiry| expiry.is_expired(&env.block))
+    {
+        return Err(ContractError::OwnershipProposalExpired);
+    }
+
+    OWNER.set(deps.branch(), Some(pending_owner.new_owner))?;
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "accept_ownership"),
+        ("owner", info.sender.as_str()),
+    ]))
+}
+
+fn execute_cancel_ownership_proposal(deps: DepsMut, info: MessageInfo) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+
+    if !PENDING_OWNER.exists(deps.storage) {
+        return Err(ContractError::NoPendingOwner);
+    }
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new().add_attribute("action", "cancel_ownership_proposal"))
 }
 
 pub fn execute_register_protocol_fee(
@@ -166,6 +747,7 @@ pub fn execute_register_protocol_fee(
     info: MessageInfo,
     percent: Decimal,
     fee_receiver: Addr,
+    fee_receivers: Vec<FeeReceiver>,
 ) -> Result<Response, ContractError> {
     OWNER.assert_admin(deps.as_ref(), &info.sender)?;
 
@@ -174,11 +756,27 @@ pub fn execute_register_protocol_fee(
         return Err(ContractError::Zapper(ZapperError::InvalidFee {}));
     }
 
+    let fee_receiver = deps.api.addr_validate(fee_receiver.as_str())?;
+    let mut total_weight: u64 = 0;
+    let mut validated_receivers: Vec<FeeReceiver> = vec![];
+    for receiver in fee_receivers {
+        // every receiver must carry a share of the fee
+        total_weight = total_weight
+            .checked_add(receiver.weight)
+            .filter(|_| receiver.weight > 0)
+            .ok_or(ContractError::InvalidFeeReceivers)?;
+        validated_receivers.push(FeeReceiver {
+            address: deps.api.addr_validate(receiver.address.as_str())?,
+            weight: receiver.weight,
+        });
+    }
+
     PROTOCOL_FEE.save(
         deps.storage,
         &ProtocolFee {
             percent,
             fee_receiver: fee_receiver.clone(),
+            fee_receivers: validated_receivers.clone(),
         },
     )?;
 
@@ -186,11 +784,251 @@ pub fn execute_register_protocol_fee(
         ("action", "register_protocol_fee"),
         ("percent", &percent.to_string()),
         ("fee_receiver", fee_receiver.as_str()),
+        ("fee_receivers", &validated_receivers.len().to_string()),
+    ]))
+}
+
+fn execute_set_max_referral_bps(
+    deps: DepsMut,
+    info: MessageInfo,
+    max_bps: u16,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+
+    // validate max bps must be <= 100%
+    if max_bps > 10_000 {
+        return Err(ContractError::Zapper(ZapperError::InvalidFee {}));
+    }
+
+    MAX_REFERRAL_BPS.save(deps.storage, &max_bps)?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "set_max_referral_bps"),
+        ("max_bps", &max_bps.to_string()),
+    ]))
+}
+
+fn execute_set_pool_fee(
+    deps: DepsMut,
+    info: MessageInfo,
+    pool_id: u64,
+    direction: FeeDirection,
+    percent: Decimal,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+
+    // validate percent must be < 1
+    if percent.gt(&Decimal::one()) {
+        return Err(ContractError::Zapper(ZapperError::InvalidFee {}));
+    }
+
+    POOL_FEES.save(deps.storage, (pool_id, direction.as_str()), &percent)?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "set_pool_fee"),
+        ("pool_id", &pool_id.to_string()),
+        ("direction", direction.as_str()),
+        ("percent", &percent.to_string()),
+    ]))
+}
+
+fn execute_remove_pool_fee(
+    deps: DepsMut,
+    info: MessageInfo,
+    pool_id: u64,
+    direction: FeeDirection,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+
+    POOL_FEES.remove(deps.storage, (pool_id, direction.as_str()));
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "remove_pool_fee"),
+        ("pool_id", &pool_id.to_string()),
+        ("direction", direction.as_str()),
+    ]))
+}
+
+fn execute_claim_fees(
+    deps: DepsMut,
+    info: MessageInfo,
+    denoms: Option<Vec<String>>,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    // pool fee overrides accrue without a global fee, only the receivers are needed
+    let protocol_fee = PROTOCOL_FEE
+        .may_load(deps.storage)?
+        .ok_or(ContractError::FeeReceiversNotConfigured)?;
+
+    let denoms: Vec<String> = match denoms {
+        Some(denoms) => denoms,
+        None => ACCRUED_FEES
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<_>>()?,
+    };
+
+    // split each accrued fee across the fee receivers, a denom listed twice is claimed once
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    for denom in denoms {
+        let Some(amount) = ACCRUED_FEES.may_load(deps.storage, &denom)? else {
+            continue;
+        };
+        ACCRUED_FEES.remove(deps.storage, &denom);
+        let asset = Asset::new(deps.api, &denom, amount);
+        for (receiver, share) in protocol_fee.split(amount) {
+            msgs.push(asset.transfer_amount(share, receiver.as_str()));
+        }
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "claim_fees")
+        .add_messages(msgs))
+}
+
+fn execute_convert_fees(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    routes: Vec<Route>,
+    minimum_receive: Uint128,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    let treasury_denom = TREASURY_DENOM
+        .may_load(deps.storage)?
+        .ok_or(ContractError::TreasuryDenomNotSet)?;
+
+    // every route swaps a native accrued fee into the treasury denom
+    for route in &routes {
+        let first_op = route
+            .operations
+            .first()
+            .ok_or(ContractError::SwapOperationsEmpty)?;
+        if first_op.denom_in != route.token_in {
+            return Err(ContractError::CoinInDenomMismatch);
+        }
+        validate_target_paths(std::slice::from_ref(&route.operations), &treasury_denom)?;
+        if route.token_in == treasury_denom {
+            return Err(ContractError::CoinInDenomMismatch);
+        }
+        if let Asset::Cw20(_) = Asset::new(deps.api, &route.token_in, route.offer_amount) {
+            return Err(ContractError::AssetNotNative);
+        }
+    }
+
+    // snapshot treasury denom
+    let nonce = next_nonce(deps.storage)?;
+    snapshot_balances(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env,
+        nonce,
+        &treasury_denom,
+    )?;
+
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    for route in routes {
+        // the swapped amount leaves the accrued fees
+        let accrued = ACCRUED_FEES
+            .may_load(deps.storage, &route.token_in)?
+            .unwrap_or_default()
+            .checked_sub(route.offer_amount)
+            .map_err(|_| ContractError::InsufficientAccruedFees(route.token_in.clone()))?;
+        if accrued.is_zero() {
+            ACCRUED_FEES.remove(deps.storage, &route.token_in);
+        } else {
+            ACCRUED_FEES.save(deps.storage, &route.token_in, &accrued)?;
+        }
+
+        msgs.push(create_osmosis_swap_msg(
+            env.contract.address.to_string(),
+            coin(route.offer_amount.u128(), route.token_in),
+            route.operations,
+            route.minimum_receive,
+        )?);
+    }
+
+    // accrue the converted amount once the swaps are done
+    msgs.push(
+        wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::AccrueConvertedFees {
+                nonce,
+                minimum_receive,
+            },
+            vec![],
+        )?
+        .into(),
+    );
+
+    Ok(Response::new()
+        .add_attribute("action", "convert_fees")
+        .add_messages(msgs))
+}
+
+fn execute_accrue_converted_fees(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    nonce: u64,
+    minimum_receive: Uint128,
+) -> ContractResult<Response> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    let treasury_denom = TREASURY_DENOM.load(deps.storage)?;
+    let balance_before = SNAP_BALANCES.load(deps.storage, (nonce, &treasury_denom))?;
+    let converted = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &treasury_denom,
+    )?
+    .amount()
+    .checked_sub(balance_before)?;
+
+    // the whole conversion reverts if the swaps returned less than expected
+    if converted < minimum_receive {
+        return Err(ContractError::MinimumReceiveNotMet {
+            denom: treasury_denom,
+            expected: minimum_receive,
+            received: converted,
+        });
+    }
+
+    ACCRUED_FEES.update(deps.storage, &treasury_denom, |accrued| -> StdResult<_> {
+        Ok(accrued.unwrap_or_default() + converted)
+    })?;
+    clear_snapshot_balances(deps.storage, nonce)?;
+
+    Ok(Response::new().add_attributes(vec![
+        ("action", "accrue_converted_fees"),
+        ("denom", &treasury_denom),
+        ("amount", &converted.to_string()),
     ]))
 }
 
+fn execute_set_treasury_denom(
+    deps: DepsMut,
+    info: MessageInfo,
+    denom: String,
+) -> ContractResult<Response> {
+    OWNER.assert_admin(deps.as_ref(), &info.sender)?;
+    // fees are swapped into the treasury denom on osmosis, so it must be native
+    if denom.is_empty() || deps.api.addr_validate(&denom).is_ok() {
+        return Err(ContractError::InvalidTreasuryDenom(denom));
+    }
+
+    TREASURY_DENOM.save(deps.storage, &denom)?;
+
+    Ok(Response::new().add_attributes(vec![("action", "set_treasury_denom"), ("denom", &denom)]))
+}
+
 pub fn execute_withdraw(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     assets: Vec<Asset>,
     recipient: Option<Addr>,
@@ -198,11 +1036,34 @@ pub fn execute_withdraw(
     OWNER.assert_admin(deps.as_ref(), &info.sender)?;
     let receiver = recipient.unwrap_or_else(|| info.sender.clone());
 
-    let mut msgs: Vec<CosmosMsg> = vec![];
-    for asset in assets {
-        msgs.push(asset.transfer(receiver.as_str()))
+    // a denom listed more than once is checked on its total
+    let mut totals: Vec<(&str, Uint128)> = vec![];
+    for asset in &assets {
+        match totals.iter_mut().find(|(denom, _)| *denom == asset.denom()) {
+            Some((_, total)) => *total = total.checked_add(asset.amount())?,
+            None => totals.push((asset.denom(), asset.amount())),
+        }
     }
 
+    // accrued fees can only leave through ClaimFees
+    for (denom, total) in totals {
+        let available = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        if total > available.amount() {
+            return Err(ContractError::WithdrawAccruedFees(denom.to_string()));
+        }
+    }
+
+    let msgs: Vec<CosmosMsg> = assets
+        .iter()
+        .map(|asset| asset.transfer(receiver.as_str()))
+        .collect();
+
     Ok(Response::new()
         .add_attribute("action", "withdraw")
         .add_messages(msgs))
@@ -213,35 +1074,202 @@ fn execute_transfer_funds_back(
     env: Env,
     info: MessageInfo,
     receiver: Addr,
+    nonce: u64,
+    minimum_receive: Vec<Coin>,
+    ibc_return: Option<IbcReturn>,
 ) -> ContractResult<Response> {
     if info.sender != env.contract.address {
         return Err(ContractError::Unauthorized {});
     }
 
-    let mut msgs: Vec<CosmosMsg> = vec![];
+    let mut refunds: Vec<Asset> = vec![];
 
-    for item in SNAP_BALANCES.range(deps.storage, None, None, Order::Ascending) {
-        let (denom, amount) = item?;
-        let current_balance =
-            get_current_asset_available(deps.api, &deps.querier, &env.contract.address, &denom)?;
+    for (denom, amount) in load_snapshot_balances(deps.storage, nonce)? {
+        let current_balance = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            &denom,
+        )?;
 
         let refund_amount = current_balance.amount().checked_sub(amount)?;
         if !refund_amount.is_zero() {
-            let asset = Asset::new(deps.api, &denom, refund_amount);
-            msgs.push(asset.transfer(receiver.as_str()));
+            refunds.push(Asset::new(deps.api, &denom, refund_amount));
+        }
+    }
+
+    // the whole zap out reverts if the receiver gets less than expected
+    for min in minimum_receive {
+        let received = refunds
+            .iter()
+            .find(|asset| asset.denom() == min.denom)
+            .map(|asset| asset.amount())
+            .unwrap_or_default();
+        if received < min.amount {
+            return Err(ContractError::MinimumReceiveNotMet {
+                denom: min.denom,
+                expected: min.amount,
+                received,
+            });
         }
     }
 
     // clear snap balances
-    SNAP_BALANCES.clear(deps.storage);
+    clear_snapshot_balances(deps.storage, nonce)?;
+
+    let mut msgs: Vec<CosmosMsg> = vec![];
+    let mut ibc_transfer_msgs: Vec<SubMsg> = vec![];
+    let mut ibc_coins: Vec<Coin> = vec![];
+    for asset in refunds {
+        match (&ibc_return, asset) {
+            // native coins are shipped back over IBC, cw20 tokens stay local
+            (Some(ibc_return), Asset::Native(refund)) => {
+                ibc_transfer_msgs.push(SubMsg::reply_on_success(
+                    create_ibc_transfer_msg(&env, ibc_return, refund.clone())?,
+                    encode_reply_id(IBC_TRANSFER_ID, nonce),
+                ));
+                ibc_coins.push(refund);
+            }
+            (_, asset) => msgs.push(asset.transfer(receiver.as_str())),
+        }
+    }
+
+    // the receiver is refunded locally if a transfer fails
+    if let Some(ibc_return) = ibc_return.filter(|_| !ibc_coins.is_empty()) {
+        PENDING_IBC_TRANSFERS.save(
+            deps.storage,
+            nonce,
+            &PendingIbcTransfer {
+                receiver,
+                channel: ibc_return.channel,
+                coins: ibc_coins,
+            },
+        )?;
+    }
+
+    Ok(Response::new()
+        .add_messages(msgs)
+        .add_submessages(ibc_transfer_msgs))
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, _env: Env, msg: SudoMsg) -> ContractResult<Response> {
+    match msg {
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+            channel,
+            sequence,
+            success,
+            ..
+        }) => ibc_lifecycle_complete(deps, channel, sequence, success),
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout { channel, sequence }) => {
+            ibc_lifecycle_complete(deps, channel, sequence, false)
+        }
+    }
+}
 
-    Ok(Response::new().add_messages(msgs))
+fn ibc_lifecycle_complete(
+    deps: DepsMut,
+    channel: String,
+    sequence: u64,
+    success: bool,
+) -> ContractResult<Response> {
+    let Some(ibc_transfer) = IBC_TRANSFERS.may_load(deps.storage, (&channel, sequence))? else {
+        return Ok(Response::new());
+    };
+    IBC_TRANSFERS.remove(deps.storage, (&channel, sequence));
+
+    if success {
+        return Ok(Response::new().add_attribute("action", "ibc_transfer_ack"));
+    }
+
+    // refund the local receiver when the transfer failed or timed out
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: ibc_transfer.receiver.to_string(),
+            amount: vec![ibc_transfer.coin],
+        })
+        .add_attributes(vec![
+            ("action", "ibc_transfer_refund"),
+            ("receiver", ibc_transfer.receiver.as_str()),
+        ]))
 }
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::Owner {} => to_json_binary(&OWNER.get(deps)?),
+        QueryMsg::PendingOwner {} => to_json_binary(&PENDING_OWNER.may_load(deps.storage)?),
         QueryMsg::ProtocolFee {} => to_json_binary(&get_protocol_fee(deps)?),
+        QueryMsg::Cw20Converter { cw20_address } => {
+            to_json_binary(&CW20_CONVERTERS.may_load(deps.storage, &cw20_address)?)
+        }
+        QueryMsg::EffectiveFee { pool_id } => to_json_binary(&EffectiveFeeResponse {
+            zap_in: protocol_fee_percent(deps.storage, pool_id, FeeDirection::ZapIn)?,
+            zap_out: protocol_fee_percent(deps.storage, pool_id, FeeDirection::ZapOut)?,
+        }),
+        QueryMsg::TreasuryDenom {} => to_json_binary(&TREASURY_DENOM.may_load(deps.storage)?),
+        QueryMsg::AccruedFees {} => to_json_binary(
+            &ACCRUED_FEES
+                .range(deps.storage, None, None, Order::Ascending)
+                .map(|item| item.map(|(denom, amount)| Asset::new(deps.api, &denom, amount)))
+                .collect::<StdResult<Vec<Asset>>>()?,
+        ),
+        QueryMsg::MaxReferralBps {} => {
+            to_json_binary(&MAX_REFERRAL_BPS.may_load(deps.storage)?.unwrap_or_default())
+        }
+        QueryMsg::ReferralFees { referrer } => to_json_binary(
+            &REFERRAL_FEES
+                .prefix(&deps.api.addr_validate(referrer.as_str())?)
+                .range(deps.storage, None, None, Order::Ascending)
+                .map(|item| item.map(|(denom, amount)| coin(amount.u128(), denom)))
+                .collect::<StdResult<Vec<Coin>>>()?,
+        ),
+        QueryMsg::PositionOwner { position_id } => {
+            to_json_binary(&POSITION_OWNERS.may_load(deps.storage, position_id)?)
+        }
+        QueryMsg::SimulateZapIn {
+            asset_in,
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            routes,
+            optimal_swap,
+            referral,
+        } => to_json_binary(&simulate_zap_in(
+            deps,
+            asset_in,
+            pool_id,
+            token_0,
+            token_1,
+            lower_tick,
+            upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            routes,
+            optimal_swap,
+            referral,
+        )?),
+        QueryMsg::SimulateZapOut {
+            position_id,
+            routes,
+            liquidity_amount,
+            percent,
+            minimum_receive,
+            referral,
+        } => to_json_binary(&simulate_zap_out(
+            deps,
+            position_id,
+            routes,
+            liquidity_amount,
+            percent,
+            minimum_receive,
+            referral,
+        )?),
     }
 }
 
@@ -259,9 +1287,19 @@ pub fn migrate(deps: DepsMut, _env: Env, _msg: MigrateMsg) -> ContractResult<Res
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> ContractResult<Response> {
-    match msg.id {
-        CREATE_POSITION_ID => reply_create_position(deps, env, msg),
-        WITHDRAW_POSITION_ID => reply_withdraw_position(deps, env, msg),
+    let (kind, nonce) = decode_reply_id(msg.id);
+    match kind {
+        CREATE_POSITION_ID => reply_create_position(deps, env, msg, nonce),
+        WITHDRAW_POSITION_ID => reply_withdraw_position(deps, env, msg, nonce),
+        JOIN_POOL_ID => reply_join_pool(deps, env, msg, nonce),
+        // exiting a pool continues like a withdrawn position
+        EXIT_POOL_ID => reply_withdraw_position(deps, env, msg, nonce),
+        // harvested rewards are swapped into the target like a zap out
+        HARVEST_REWARDS_ID => reply_withdraw_position(deps, env, msg, nonce),
+        IBC_ZAP_IN_ID => reply_ibc_zap_in(deps, msg, nonce),
+        IBC_TRANSFER_ID => reply_ibc_transfer(deps, msg, nonce),
+        ADD_TO_POSITION_ID => reply_add_to_position(deps, env, msg, nonce),
+        MIGRATE_POSITION_ID => reply_migrate_position(deps, env, msg, nonce),
         _ => Err(ContractError::Zapper(ZapperError::ReplyIdError(msg.id))),
     }
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
 use thiserror::Error;
 use zapper::error::ZapperError;
 
@@ -24,6 +24,12 @@ pub enum ContractError {
     #[error("Unauthorized")]
     Unauthorized,
 
+    #[error("No ownership proposal is pending")]
+    NoPendingOwner,
+
+    #[error("Ownership proposal expired")]
+    OwnershipProposalExpired,
+
     #[error("Parse Int error raised: invalid pool String to pool id u64 conversion")]
     ParseIntPoolID(#[from] std::num::ParseIntError),
 
@@ -44,6 +50,82 @@ pub enum ContractError {
 
     #[error("Create position error {0}")]
     WithdrawPositionError(String),
+
+    #[error("Add to position error {0}")]
+    AddToPositionError(String),
+
+    #[error("IBC memo must be a JSON object without an ibc_callback key")]
+    InvalidIbcMemo,
+
+    #[error("IBC return needs a channel and a timeout greater than zero")]
+    InvalidIbcReturn,
+
+    #[error("Migration paths must swap between the position tokens")]
+    InvalidMigrationRoutes,
+
+    #[error("Max slippage must not exceed 1")]
+    InvalidMaxSlippage,
+
+    #[error("Pool {0} is not a concentrated liquidity pool")]
+    InvalidConcentratedPool(u64),
+
+    #[error("Pool tokens do not match token_0 and token_1")]
+    PoolTokensMismatch,
+
+    #[error("Optimal swap requires exactly one route to {0}")]
+    InvalidOptimalSwapRoutes(String),
+
+    #[error("Optimal swap route to {0} needs an offer amount to scale its minimum receive")]
+    InvalidOptimalSwapMinimum(String),
+
+    #[error("Only one of liquidity_amount and percent can be set")]
+    ZapOutLiquidityAndPercentSet,
+
+    #[error("Position {0} is not held by the contract")]
+    PositionNotHeld(u64),
+
+    #[error("Zap out liquidity must be greater than zero and not exceed the position liquidity")]
+    InvalidZapOutLiquidity,
+
+    #[error("Pool {0} is not a GAMM balancer or stableswap pool")]
+    InvalidGammPool(u64),
+
+    #[error("Join pool error {0}")]
+    JoinPoolError(String),
+
+    #[error("{0} is not a GAMM pool share denom")]
+    InvalidGammShareDenom(String),
+
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("Cannot withdraw accrued fees of {0}")]
+    WithdrawAccruedFees(String),
+
+    #[error("Not enough accrued fees of {0} to convert")]
+    InsufficientAccruedFees(String),
+
+    #[error("Fee receivers are not configured")]
+    FeeReceiversNotConfigured,
+
+    #[error("Treasury denom is not set")]
+    TreasuryDenomNotSet,
+
+    #[error("Treasury denom \"{0}\" must be a non-empty native denom")]
+    InvalidTreasuryDenom(String),
+
+    #[error("Fee receiver weights must be greater than zero and sum to at most u64::MAX")]
+    InvalidFeeReceivers,
+
+    #[error("Referral fee of {bps} bps exceeds the maximum of {max_bps} bps")]
+    ReferralFeeTooHigh { bps: u16, max_bps: u16 },
+
+    #[error("Minimum receive not met for {denom}: expected {expected}, received {received}")]
+    MinimumReceiveNotMet {
+        denom: String,
+        expected: Uint128,
+        received: Uint128,
+    },
 }
 
 impl From<ContractError> for StdError {
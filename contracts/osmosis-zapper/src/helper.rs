@@ -1,16 +1,39 @@
-use cosmwasm_std::{Coin, CosmosMsg, Deps, Env, Uint128};
-use osmosis_std::types::osmosis::{
-    gamm::v1beta1::MsgSwapExactAmountIn, poolmanager::v1beta1::SwapAmountInRoute,
+use std::str::FromStr;
+
+use cosmwasm_std::{
+    Addr, Api, Coin, CosmosMsg, Decimal, Decimal256, Deps, Env, StdError, StdResult, Storage,
+    Uint128,
+};
+use cw_utils::Expiration;
+use osmosis_std::types::{
+    cosmos::authz::v1beta1::MsgExec,
+    ibc::applications::transfer::v1::MsgTransfer,
+    osmosis::{
+        concentratedliquidity::v1beta1::{
+            FullPositionBreakdown, MsgCollectIncentives, MsgCollectSpreadRewards,
+            MsgTransferPositions, Pool as ConcentratedPool, Position,
+        },
+        gamm::{
+            poolmodels::stableswap::v1beta1::Pool as StableswapPool,
+            v1beta1::{MsgSwapExactAmountIn, Pool as BalancerPool},
+        },
+        poolmanager::v1beta1::{PoolmanagerQuerier, SwapAmountInRoute},
+    },
 };
 use zapper::{
-    asset::{get_current_asset_available, Asset},
+    asset::Asset,
+    error::ZapperError,
     proto_coin::ProtoCoin,
-    swap::{convert_swap_operations, SwapOperation},
+    swap::{convert_swap_operations, Route, SwapOperation},
+    tick_math::{parse_osmosis_decimal, tick_to_sqrt_price, token_0_share},
 };
 
 use crate::{
     error::{ContractError, ContractResult},
-    state::SNAP_BALANCES,
+    state::{
+        get_available_balance, protocol_fee_percent, FeeDirection, IbcReturn, Referral,
+        ACCRUED_FEES, MAX_REFERRAL_BPS, POSITION_OWNERS, REFERRAL_FEES, SNAP_BALANCES,
+    },
 };
 
 pub fn create_osmosis_swap_msg(
@@ -40,19 +63,125 @@ pub fn create_osmosis_swap_msg(
     Ok(swap_msg)
 }
 
+// Deducts the effective protocol fee of the pool from amount of asset and accrues it
+// until it is claimed, returning the amount left
+pub fn deduct_protocol_fee(
+    storage: &mut dyn Storage,
+    pool_id: u64,
+    direction: FeeDirection,
+    asset: &Asset,
+    amount: Uint128,
+) -> ContractResult<Uint128> {
+    let fee_amount = amount * protocol_fee_percent(storage, pool_id, direction)?;
+    if !fee_amount.is_zero() {
+        ACCRUED_FEES.update(storage, asset.denom(), |accrued| -> StdResult<_> {
+            Ok(accrued.unwrap_or_default() + fee_amount)
+        })?;
+    }
+    Ok(amount.checked_sub(fee_amount)?)
+}
+
+// Deducts the referral fee from amount of asset, on top of the protocol fee, queues
+// its transfer to the referrer and adds it to the referrer totals
+pub fn deduct_referral_fee(
+    storage: &mut dyn Storage,
+    referral: Option<&Referral>,
+    asset: &Asset,
+    amount: Uint128,
+    msgs: &mut Vec<CosmosMsg>,
+) -> ContractResult<Uint128> {
+    let Some(referral) = referral else {
+        return Ok(amount);
+    };
+
+    let fee_amount = referral.fee_amount(amount);
+    if !fee_amount.is_zero() {
+        msgs.push(asset.transfer_amount(fee_amount, referral.address.as_str()));
+        REFERRAL_FEES.update(
+            storage,
+            (&referral.address, asset.denom()),
+            |total| -> StdResult<_> { Ok(total.unwrap_or_default() + fee_amount) },
+        )?;
+    }
+    Ok(amount.checked_sub(fee_amount)?)
+}
+
+// Checks the referral against the cap and returns it with a validated address,
+// so referral fees are paid and keyed by the canonical referrer
+pub fn validate_referral(
+    api: &dyn Api,
+    storage: &dyn Storage,
+    referral: Option<Referral>,
+) -> ContractResult<Option<Referral>> {
+    let Some(referral) = referral else {
+        return Ok(None);
+    };
+
+    let max_bps = MAX_REFERRAL_BPS.may_load(storage)?.unwrap_or_default();
+    if referral.bps > max_bps {
+        return Err(ContractError::ReferralFeeTooHigh {
+            bps: referral.bps,
+            max_bps,
+        });
+    }
+    Ok(Some(Referral {
+        address: api.addr_validate(referral.address.as_str())?,
+        bps: referral.bps,
+    }))
+}
+
+pub fn estimate_swap_exact_amount_in(
+    deps: &Deps,
+    coin_in: Coin,
+    swap_operations: Vec<SwapOperation>,
+) -> ContractResult<Uint128> {
+    let osmosis_swap_amount_in_routes: Vec<SwapAmountInRoute> =
+        convert_swap_operations(swap_operations).map_err(ContractError::ParseIntPoolID)?;
+    let pool_id = osmosis_swap_amount_in_routes
+        .first()
+        .map(|route| route.pool_id)
+        .ok_or(ContractError::SwapOperationsEmpty)?;
+
+    let token_out_amount = PoolmanagerQuerier::new(&deps.querier)
+        .estimate_swap_exact_amount_in(pool_id, coin_in.to_string(), osmosis_swap_amount_in_routes)?
+        .token_out_amount;
+
+    Ok(Uint128::from_str(&token_out_amount)?)
+}
+
+// Every zap in route must start from the sent asset
+pub fn validate_zap_in_routes(routes: &[Route], denom_in: &str) -> ContractResult<()> {
+    for route in routes {
+        let first_op = route
+            .operations
+            .first()
+            .ok_or(ContractError::SwapOperationsEmpty)?;
+        if route.token_in != denom_in || first_op.denom_in != denom_in {
+            return Err(ContractError::CoinInDenomMismatch);
+        }
+    }
+    Ok(())
+}
+
 pub fn create_refund_msg(
     deps: &Deps,
     env: &Env,
+    nonce: u64,
     denom: &str,
     receiver: &str,
 ) -> ContractResult<Option<CosmosMsg>> {
     // query snapshot balances
-    let balance_before = SNAP_BALANCES.load(deps.storage, denom)?;
+    let balance_before = SNAP_BALANCES.load(deps.storage, (nonce, denom))?;
 
     // query balance after
-    let balance_after =
-        get_current_asset_available(deps.api, &deps.querier, &env.contract.address, denom)?
-            .amount();
+    let balance_after = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        denom,
+    )?
+    .amount();
 
     if balance_after > balance_before {
         let refund_amount = balance_after - balance_before;
@@ -62,3 +191,372 @@ pub fn create_refund_msg(
     }
     Ok(None)
 }
+
+pub fn query_concentrated_pool(deps: &Deps, pool_id: u64) -> ContractResult<ConcentratedPool> {
+    let pool = PoolmanagerQuerier::new(&deps.querier)
+        .pool(pool_id)?
+        .pool
+        .ok_or(ContractError::InvalidConcentratedPool(pool_id))?;
+
+    if pool.type_url != ConcentratedPool::TYPE_URL {
+        return Err(ContractError::InvalidConcentratedPool(pool_id));
+    }
+
+    ConcentratedPool::try_from(pool).map_err(|_| ContractError::InvalidConcentratedPool(pool_id))
+}
+
+pub fn gamm_share_denom(pool_id: u64) -> String {
+    format!("gamm/pool/{}", pool_id)
+}
+
+pub fn parse_gamm_share_denom(denom: &str) -> ContractResult<u64> {
+    let pool_id = denom
+        .strip_prefix("gamm/pool/")
+        .ok_or_else(|| ContractError::InvalidGammShareDenom(denom.to_string()))?;
+    Ok(pool_id.parse()?)
+}
+
+// Returns the denoms of a GAMM balancer or stableswap pool, other pool types are rejected
+pub fn query_gamm_pool_denoms(deps: &Deps, pool_id: u64) -> ContractResult<Vec<String>> {
+    let querier = PoolmanagerQuerier::new(&deps.querier);
+    let pool = querier
+        .pool(pool_id)?
+        .pool
+        .ok_or(ContractError::InvalidGammPool(pool_id))?;
+
+    if pool.type_url != BalancerPool::TYPE_URL && pool.type_url != StableswapPool::TYPE_URL {
+        return Err(ContractError::InvalidGammPool(pool_id));
+    }
+
+    Ok(querier
+        .total_pool_liquidity(pool_id)?
+        .liquidity
+        .into_iter()
+        .map(|coin| coin.denom)
+        .collect())
+}
+
+// Splits amount_in between token_0 and token_1 according to the pool's current
+// price and the position's tick range, then assigns each part to the route that
+// ends in that token. The offer amounts given by the caller are ignored.
+#[allow(clippy::too_many_arguments)]
+pub fn build_optimal_swap_routes(
+    deps: &Deps,
+    pool_id: u64,
+    token_0: &str,
+    token_1: &str,
+    lower_tick: i64,
+    upper_tick: i64,
+    denom_in: &str,
+    amount_in: Uint128,
+    routes: &[Route],
+) -> ContractResult<Vec<Route>> {
+    let pool = query_concentrated_pool(deps, pool_id)?;
+    if pool.token0 != token_0 || pool.token1 != token_1 {
+        return Err(ContractError::PoolTokensMismatch);
+    }
+
+    let share_0 = token_0_share(
+        parse_osmosis_decimal(&pool.current_sqrt_price)?,
+        tick_to_sqrt_price(lower_tick)?,
+        tick_to_sqrt_price(upper_tick)?,
+    )?;
+    let amount_0 = amount_in * share_0;
+    let amount_1 = amount_in.checked_sub(amount_0)?;
+
+    let mut optimal_routes: Vec<Route> = vec![];
+    for (token, amount) in [(token_0, amount_0), (token_1, amount_1)] {
+        // the input is already in this token, nothing to swap
+        if amount.is_zero() || token == denom_in {
+            continue;
+        }
+
+        let mut matched = routes
+            .iter()
+            .filter(|route| route.ask_denom().is_ok_and(|denom| denom == token));
+        let route = match (matched.next(), matched.next()) {
+            (Some(route), None) => route,
+            _ => return Err(ContractError::InvalidOptimalSwapRoutes(token.to_string())),
+        };
+
+        // the minimum was quoted for the caller's offer amount, scale it to the computed one
+        let minimum_receive = match route.minimum_receive {
+            Some(_) if route.offer_amount.is_zero() => {
+                return Err(ContractError::InvalidOptimalSwapMinimum(token.to_string()))
+            }
+            Some(minimum_receive) => {
+                Some(minimum_receive.multiply_ratio(amount, route.offer_amount))
+            }
+            None => None,
+        };
+        optimal_routes.push(Route {
+            offer_amount: amount,
+            minimum_receive,
+            ..route.clone()
+        });
+    }
+
+    Ok(optimal_routes)
+}
+
+// Returns the coin to swap so that amount_0 and amount_1 match the token 0 share of a
+// position on the tick range at the pool price, none when they are already balanced
+pub fn compute_rebalance_swap(
+    pool: &ConcentratedPool,
+    lower_tick: i64,
+    upper_tick: i64,
+    amount_0: Uint128,
+    amount_1: Uint128,
+) -> ContractResult<Option<Coin>> {
+    let current_sqrt_price = parse_osmosis_decimal(&pool.current_sqrt_price)?;
+    let share_0 = Decimal256::from(token_0_share(
+        current_sqrt_price,
+        tick_to_sqrt_price(lower_tick)?,
+        tick_to_sqrt_price(upper_tick)?,
+    )?);
+    let price = current_sqrt_price * current_sqrt_price;
+    if price.is_zero() {
+        return Ok(None);
+    }
+
+    // value everything in token 1
+    let value_0 = Decimal256::from_ratio(amount_0, 1u128) * price;
+    let value_1 = Decimal256::from_ratio(amount_1, 1u128);
+    let target_value_0 = (value_0 + value_1) * share_0;
+
+    let (denom, amount) = if value_0 > target_value_0 {
+        (&pool.token0, (value_0 - target_value_0) / price)
+    } else {
+        (&pool.token1, target_value_0 - value_0)
+    };
+    let amount = Uint128::try_from(amount.to_uint_floor()).map_err(StdError::from)?;
+    if amount.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(Coin::new(amount.u128(), denom)))
+}
+
+// Minimum the rebalance swap of coin_in must return, the pool price value of coin_in
+// less max_slippage
+pub fn rebalance_minimum_receive(
+    pool: &ConcentratedPool,
+    coin_in: &Coin,
+    max_slippage: Decimal,
+) -> ContractResult<Uint128> {
+    let current_sqrt_price = parse_osmosis_decimal(&pool.current_sqrt_price)?;
+    let price = current_sqrt_price * current_sqrt_price;
+    let amount_in = Decimal256::from_ratio(coin_in.amount, 1u128);
+    let expected = if coin_in.denom == pool.token0 {
+        amount_in * price
+    } else {
+        amount_in
+            .checked_div(price)
+            .map_err(|e| StdError::generic_err(e.to_string()))?
+    };
+    let minimum = expected * Decimal256::from(Decimal::one() - max_slippage);
+    Ok(Uint128::try_from(minimum.to_uint_floor()).map_err(StdError::from)?)
+}
+
+// Pulls the position from its owner to the contract. The owner must have granted
+// the contract an authz authorization for MsgTransferPositions beforehand.
+pub fn create_authz_transfer_position_msg(env: &Env, owner: &Addr, position_id: u64) -> CosmosMsg {
+    MsgExec {
+        grantee: env.contract.address.to_string(),
+        msgs: vec![MsgTransferPositions {
+            position_ids: vec![position_id],
+            sender: owner.to_string(),
+            new_owner: env.contract.address.to_string(),
+        }
+        .to_any()],
+    }
+    .into()
+}
+
+// Adds the ibc-hooks callback of the contract to the memo, which must be empty or a JSON object
+pub fn ibc_callback_memo(env: &Env, memo: Option<String>) -> ContractResult<String> {
+    let mut fields: serde_json::Map<String, serde_json::Value> =
+        match memo.as_deref().map(str::trim) {
+            None | Some("") => serde_json::Map::new(),
+            Some(memo) => serde_json::from_str(memo).map_err(|_| ContractError::InvalidIbcMemo)?,
+        };
+    // the ack and timeout must come back to this contract for the refund to run
+    if fields.contains_key("ibc_callback") {
+        return Err(ContractError::InvalidIbcMemo);
+    }
+    fields.insert(
+        "ibc_callback".to_string(),
+        serde_json::Value::String(env.contract.address.to_string()),
+    );
+    serde_json::to_string(&fields).map_err(|_| ContractError::InvalidIbcMemo)
+}
+
+// Rejects an ibc return whose transfer could never be sent, before the zap out starts
+pub fn validate_ibc_return(ibc_return: Option<&IbcReturn>) -> ContractResult<()> {
+    match ibc_return {
+        Some(ibc_return) if ibc_return.channel.is_empty() || ibc_return.timeout == 0 => {
+            Err(ContractError::InvalidIbcReturn)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn create_ibc_transfer_msg(
+    env: &Env,
+    ibc_return: &IbcReturn,
+    coin: Coin,
+) -> ContractResult<CosmosMsg> {
+    Ok(MsgTransfer {
+        source_port: "transfer".to_string(),
+        source_channel: ibc_return.channel.clone(),
+        token: Some(coin.into()),
+        sender: env.contract.address.to_string(),
+        receiver: ibc_return.receiver.clone(),
+        timeout_height: None,
+        timeout_timestamp: env.block.time.plus_seconds(ibc_return.timeout).nanos(),
+        memo: ibc_callback_memo(env, ibc_return.memo.clone())?,
+    }
+    .into())
+}
+
+// Every path must swap into the target denom
+pub fn validate_target_paths(
+    paths: &[Vec<SwapOperation>],
+    target_denom: &str,
+) -> ContractResult<()> {
+    for path in paths {
+        match path.last() {
+            None => return Err(ContractError::SwapOperationsEmpty),
+            Some(op) if op.denom_out != target_denom => {
+                return Err(ContractError::CoinOutDenomMismatch)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Claimable spread rewards and incentives of a position, merged by denom
+pub fn claimable_rewards(position_detail: &FullPositionBreakdown) -> ContractResult<Vec<Coin>> {
+    let mut rewards: Vec<Coin> = vec![];
+    for reward in position_detail
+        .claimable_spread_rewards
+        .iter()
+        .chain(position_detail.claimable_incentives.iter())
+    {
+        let amount = Uint128::from_str(&reward.amount)?;
+        match rewards.iter_mut().find(|c| c.denom == reward.denom) {
+            Some(c) => c.amount += amount,
+            None => rewards.push(Coin {
+                denom: reward.denom.clone(),
+                amount,
+            }),
+        }
+    }
+    Ok(rewards)
+}
+
+// Collects the spread rewards and incentives of a position held by the contract
+pub fn create_collect_rewards_msgs(env: &Env, position_id: u64) -> Vec<CosmosMsg> {
+    vec![
+        MsgCollectSpreadRewards {
+            position_ids: vec![position_id],
+            sender: env.contract.address.to_string(),
+        }
+        .into(),
+        MsgCollectIncentives {
+            position_ids: vec![position_id],
+            sender: env.contract.address.to_string(),
+        }
+        .into(),
+    ]
+}
+
+// Reward routes draw from the claimable rewards and must swap into one of denoms_out
+pub fn validate_reward_routes(
+    routes: &[Route],
+    rewards: &[Coin],
+    denoms_out: &[&str],
+) -> ContractResult<()> {
+    for route in routes {
+        let first_op = route
+            .operations
+            .first()
+            .ok_or(ContractError::SwapOperationsEmpty)?;
+        if first_op.denom_in != route.token_in
+            || !rewards.iter().any(|reward| reward.denom == route.token_in)
+        {
+            return Err(ContractError::CoinInDenomMismatch);
+        }
+        if !denoms_out.contains(&route.ask_denom()?.as_str()) {
+            return Err(ContractError::CoinOutDenomMismatch);
+        }
+    }
+
+    for reward in rewards {
+        let total_swap_amount: Uint128 = routes
+            .iter()
+            .filter(|route| route.token_in == reward.denom)
+            .map(|route| route.offer_amount)
+            .sum();
+        if total_swap_amount > reward.amount {
+            return Err(ContractError::Zapper(
+                ZapperError::ZapOutNotEnoughBalanceToSwap {},
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Escrows the position on behalf of sender. When sender still owns the position it
+// is pulled through authz, otherwise the contract must already hold it for sender.
+pub fn escrow_position(
+    storage: &mut dyn Storage,
+    env: &Env,
+    sender: &Addr,
+    position: &Position,
+) -> ContractResult<Option<CosmosMsg>> {
+    if position.address == sender.as_str() {
+        POSITION_OWNERS.save(storage, position.position_id, sender)?;
+        return Ok(Some(create_authz_transfer_position_msg(
+            env,
+            sender,
+            position.position_id,
+        )));
+    }
+
+    if position.address != env.contract.address.as_str()
+        || POSITION_OWNERS
+            .may_load(storage, position.position_id)?
+            .as_ref()
+            != Some(sender)
+    {
+        return Err(ContractError::Unauthorized {});
+    }
+    Ok(None)
+}
+
+// Resolves the liquidity to withdraw from a position, defaulting to all of it
+pub fn resolve_withdraw_liquidity(
+    position_liquidity: Decimal256,
+    liquidity_amount: Option<Decimal256>,
+    percent: Option<Decimal>,
+) -> ContractResult<Decimal256> {
+    let withdraw_liquidity = match (liquidity_amount, percent) {
+        (Some(_), Some(_)) => return Err(ContractError::ZapOutLiquidityAndPercentSet),
+        (Some(liquidity_amount), None) => liquidity_amount,
+        (None, Some(percent)) => position_liquidity * Decimal256::from(percent),
+        (None, None) => position_liquidity,
+    };
+    if withdraw_liquidity.is_zero() || withdraw_liquidity > position_liquidity {
+        return Err(ContractError::InvalidZapOutLiquidity);
+    }
+    Ok(withdraw_liquidity)
+}
+
+// Rejects the zap once the optional deadline has passed
+pub fn assert_deadline(env: &Env, deadline: Option<Expiration>) -> ContractResult<()> {
+    match deadline {
+        Some(deadline) if deadline.is_expired(&env.block) => Err(ContractError::DeadlineExceeded),
+        _ => Ok(()),
+    }
+}
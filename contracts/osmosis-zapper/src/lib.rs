@@ -3,5 +3,9 @@ pub mod error;
 pub mod helper;
 pub mod msg;
 pub mod reply;
+pub mod simulate;
 pub mod state;
 pub mod zap;
+
+#[cfg(test)]
+mod tests;
@@ -1,9 +1,15 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Addr, Decimal, Uint128};
-use cw20::Cw20ReceiveMsg;
-use zapper::{asset::Asset, swap::Route};
+use cosmwasm_std::{Addr, Coin, Decimal, Decimal256, Uint128};
+use cw20::{Cw20Coin, Cw20ReceiveMsg};
+use cw_utils::Expiration;
+use zapper::{
+    asset::Asset,
+    swap::{Route, SwapOperation},
+};
 
-use crate::state::ProtocolFee;
+use crate::state::{
+    Cw20Converter, FeeDirection, FeeReceiver, IbcReturn, PendingOwner, ProtocolFee, Referral,
+};
 
 #[cw_serde]
 pub struct InstantiateMsg {
@@ -13,9 +19,13 @@ pub struct InstantiateMsg {
 #[cw_serde]
 pub enum ExecuteMsg {
     Receive(Cw20ReceiveMsg),
-    ChangeOwner {
+    /// Proposes a new owner, who becomes owner once the proposal is accepted
+    ProposeNewOwner {
         new_owner: Addr,
+        expiry: Option<Expiration>,
     },
+    AcceptOwnership {},
+    CancelOwnershipProposal {},
     ZapInLiquidity {
         pool_id: u64,
         token_0: String,
@@ -25,6 +35,30 @@ pub enum ExecuteMsg {
         token_min_amount_0: Option<Uint128>,
         token_min_amount_1: Option<Uint128>,
         routes: Vec<Route>,
+        /// When set, routes only describe swap paths and their offer amounts are
+        /// computed from the pool's current price and the tick range. A route minimum
+        /// is scaled from its offer amount to the computed one.
+        optimal_swap: Option<bool>,
+        /// Block time or height after which the zap is rejected.
+        deadline: Option<Expiration>,
+        /// Integrator earning a fee in bps of the zapped amount, on top of the protocol fee.
+        referral: Option<Referral>,
+    },
+    /// Zaps every coin sent as funds, and the optional CW20 pulled from the sender's
+    /// allowance, into a concentrated position. Routes draw from any sent coin. The CW20
+    /// must have a registered converter and is zapped as its native counterpart.
+    ZapInLiquidityMulti {
+        pool_id: u64,
+        token_0: String,
+        token_1: String,
+        lower_tick: i64,
+        upper_tick: i64,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        cw20_asset: Option<Cw20Coin>,
+        routes: Vec<Route>,
+        deadline: Option<Expiration>,
+        referral: Option<Referral>,
     },
     CreatePosition {
         pool_id: u64,
@@ -34,23 +68,188 @@ pub enum ExecuteMsg {
         upper_tick: i64,
         token_min_amount_0: Option<Uint128>,
         token_min_amount_1: Option<Uint128>,
+        nonce: u64,
+    },
+    /// Swaps the sent coin into the tokens of an escrowed position and adds them to it,
+    /// the position is returned under its new id.
+    ZapIntoPosition {
+        position_id: u64,
+        routes: Vec<Route>,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        deadline: Option<Expiration>,
+        referral: Option<Referral>,
+    },
+    AddToPosition {
+        position_id: u64,
+        token_0: String,
+        token_1: String,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        nonce: u64,
+    },
+    /// Withdraws the whole position, swaps the imbalance the new tick range needs at the
+    /// pool price along the path starting at the surplus token and creates a new position
+    /// in the same pool on the new tick range.
+    MigratePosition {
+        position_id: u64,
+        new_lower_tick: i64,
+        new_upper_tick: i64,
+        paths: Vec<Vec<SwapOperation>>,
+        /// Share below the pool price value of the surplus that the rebalance swap may lose.
+        max_slippage: Decimal,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        deadline: Option<Expiration>,
+    },
+    /// Collects the rewards of the position, swaps them into the position tokens along
+    /// routes and adds them back, the position is returned under its new id.
+    AutoCompound {
+        position_id: u64,
+        routes: Vec<Route>,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        deadline: Option<Expiration>,
+    },
+    /// Collects the rewards of the position and swaps them into target_denom, the
+    /// liquidity is left untouched and the position is handed back.
+    HarvestRewards {
+        position_id: u64,
+        target_denom: String,
+        paths: Vec<Vec<SwapOperation>>,
+        minimum_receive: Option<Uint128>,
+        deadline: Option<Expiration>,
+        ibc_return: Option<IbcReturn>,
+    },
+    ZapInGammPool {
+        pool_id: u64,
+        routes: Vec<Route>,
+        share_out_min_amount: Option<Uint128>,
+        deadline: Option<Expiration>,
+        referral: Option<Referral>,
+    },
+    JoinGammPool {
+        pool_id: u64,
+        share_out_min_amount: Option<Uint128>,
+        nonce: u64,
     },
     ZapOutLiquidity {
         position_id: u64,
         routes: Vec<Route>,
+        /// Liquidity to withdraw, defaults to the whole position.
+        liquidity_amount: Option<Decimal256>,
+        /// Share of the position liquidity to withdraw, cannot be combined with liquidity_amount.
+        percent: Option<Decimal>,
+        /// Minimum total amounts the sender must receive once all routes are swapped.
+        minimum_receive: Option<Vec<Coin>>,
+        deadline: Option<Expiration>,
+        /// Sends the proceeds to a remote chain instead of the sender.
+        ibc_return: Option<IbcReturn>,
+        referral: Option<Referral>,
+    },
+    /// Exits the GAMM pool of the LP shares sent as funds
+    ZapOutGammPool {
+        routes: Vec<Route>,
+        minimum_receive: Option<Vec<Coin>>,
+        deadline: Option<Expiration>,
+        ibc_return: Option<IbcReturn>,
+        referral: Option<Referral>,
+    },
+    /// Withdraws the whole position and swaps everything received into target_denom
+    ZapOutTo {
+        position_id: u64,
+        target_denom: String,
+        paths: Vec<Vec<SwapOperation>>,
+        minimum_receive: Option<Uint128>,
+        deadline: Option<Expiration>,
+        ibc_return: Option<IbcReturn>,
+        referral: Option<Referral>,
     },
     TransferFundsBack {
         receiver: Addr,
+        nonce: u64,
+        minimum_receive: Vec<Coin>,
+        ibc_return: Option<IbcReturn>,
+    },
+    /// Continues a CW20 zap in once the CW20 has been converted to native_denom
+    ZapInConverted {
+        receiver: Addr,
+        nonce: u64,
+        native_denom: String,
+        msg: Cw20HookMsg,
+    },
+    /// Entry point of ibc-hooks memos: zaps the received coin into a position for receiver,
+    /// the coin is sent to recovery_address if the zap fails
+    IbcHookZapIn {
+        receiver: String,
+        recovery_address: String,
+        msg: Cw20HookMsg,
+    },
+    ZapInFor {
+        receiver: Addr,
+        msg: Cw20HookMsg,
+    },
+    /// Sets or removes the converter used to turn the CW20 into a native coin on receive
+    RegisterCw20Converter {
+        cw20_address: Addr,
+        converter: Option<Cw20Converter>,
     },
+    /// Records the depositor of a position the contract already holds, for positions
+    /// transferred in directly instead of pulled through authz
+    RegisterPositionOwner {
+        position_id: u64,
+        owner: Addr,
+    },
+    /// Overrides the protocol fee percent of a pool for one direction
+    SetPoolFee {
+        pool_id: u64,
+        direction: FeeDirection,
+        percent: Decimal,
+    },
+    RemovePoolFee {
+        pool_id: u64,
+        direction: FeeDirection,
+    },
+    /// Caps the referral fee zaps can carry, in bps
+    SetMaxReferralBps {
+        max_bps: u16,
+    },
+    /// Fees are split by weight across fee_receivers, fee_receiver takes the rounding dust.
+    /// A zero percent only configures the receivers of the pool fee overrides.
     RegisterProtocolFee {
         percent: Decimal,
         fee_receiver: Addr,
+        #[serde(default)]
+        fee_receivers: Vec<FeeReceiver>,
+    },
+    /// Sends the accrued fees of the denoms, or of every denom, to the fee receivers
+    ClaimFees {
+        denoms: Option<Vec<String>>,
+    },
+    /// Swaps accrued fees into the treasury denom, the converted amount stays accrued
+    ConvertFees {
+        routes: Vec<Route>,
+        minimum_receive: Uint128,
+    },
+    AccrueConvertedFees {
+        nonce: u64,
+        minimum_receive: Uint128,
+    },
+    SetTreasuryDenom {
+        denom: String,
     },
+    /// Withdraws contract balances, accrued fees excluded
     Withdraw {
         assets: Vec<Asset>,
         recipient: Option<Addr>,
     },
 }
+/// Hook sent with the CW20 to its converter, which sends back the native counterpart
+#[cw_serde]
+pub enum ConverterHookMsg {
+    Convert {},
+}
+
 /// This structure describes a CW20 hook message.
 #[cw_serde]
 pub enum Cw20HookMsg {
@@ -63,6 +262,26 @@ pub enum Cw20HookMsg {
         token_min_amount_0: Option<Uint128>,
         token_min_amount_1: Option<Uint128>,
         routes: Vec<Route>,
+        /// When set, routes only describe swap paths and their offer amounts are
+        /// computed from the pool's current price and the tick range. A route minimum
+        /// is scaled from its offer amount to the computed one.
+        optimal_swap: Option<bool>,
+        /// Block time or height after which the zap is rejected.
+        deadline: Option<Expiration>,
+        /// Integrator earning a fee in bps of the zapped amount, on top of the protocol fee.
+        referral: Option<Referral>,
+    },
+    ZapInLiquidityMulti {
+        pool_id: u64,
+        token_0: String,
+        token_1: String,
+        lower_tick: i64,
+        upper_tick: i64,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        routes: Vec<Route>,
+        deadline: Option<Expiration>,
+        referral: Option<Referral>,
     },
 }
 
@@ -71,8 +290,102 @@ pub enum Cw20HookMsg {
 pub enum QueryMsg {
     #[returns(Addr)]
     Owner {},
+    #[returns(Option<PendingOwner>)]
+    PendingOwner {},
     #[returns(ProtocolFee)]
     ProtocolFee {},
+    #[returns(EffectiveFeeResponse)]
+    EffectiveFee { pool_id: u64 },
+    #[returns(Vec<Asset>)]
+    AccruedFees {},
+    #[returns(Option<String>)]
+    TreasuryDenom {},
+    #[returns(u16)]
+    MaxReferralBps {},
+    /// Total referral fees paid to the referrer
+    #[returns(Vec<Coin>)]
+    ReferralFees { referrer: Addr },
+    #[returns(Option<Addr>)]
+    PositionOwner { position_id: u64 },
+    #[returns(Option<Cw20Converter>)]
+    Cw20Converter { cw20_address: Addr },
+    #[returns(SimulateZapInResponse)]
+    SimulateZapIn {
+        asset_in: Asset,
+        pool_id: u64,
+        token_0: String,
+        token_1: String,
+        lower_tick: i64,
+        upper_tick: i64,
+        token_min_amount_0: Option<Uint128>,
+        token_min_amount_1: Option<Uint128>,
+        routes: Vec<Route>,
+        optimal_swap: Option<bool>,
+        referral: Option<Referral>,
+    },
+    #[returns(SimulateZapOutResponse)]
+    SimulateZapOut {
+        position_id: u64,
+        routes: Vec<Route>,
+        liquidity_amount: Option<Decimal256>,
+        percent: Option<Decimal>,
+        minimum_receive: Option<Vec<Coin>>,
+        referral: Option<Referral>,
+    },
+}
+
+#[cw_serde]
+pub struct EffectiveFeeResponse {
+    pub zap_in: Decimal,
+    pub zap_out: Decimal,
+}
+
+#[cw_serde]
+pub struct RouteSimulation {
+    pub token_in: String,
+    pub offer_amount: Uint128,
+    pub token_out: String,
+    pub return_amount: Uint128,
+}
+
+/// Swap estimates assume the pool price of the position is not moved by the routes.
+#[cw_serde]
+pub struct SimulateZapInResponse {
+    pub protocol_fee: Coin,
+    pub referral_fee: Coin,
+    pub routes: Vec<RouteSimulation>,
+    pub tokens_provided: Vec<Coin>,
+    pub liquidity: Decimal256,
+    pub refunds: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct SimulateZapOutResponse {
+    pub protocol_fees: Vec<Coin>,
+    pub referral_fees: Vec<Coin>,
+    pub withdrawn: Vec<Coin>,
+    pub routes: Vec<RouteSimulation>,
+    pub refunds: Vec<Coin>,
+}
+
+/// Callbacks of ibc-hooks for the transfers sent by the contract
+#[cw_serde]
+pub enum SudoMsg {
+    IbcLifecycleComplete(IbcLifecycleComplete),
+}
+
+#[cw_serde]
+pub enum IbcLifecycleComplete {
+    IbcAck {
+        channel: String,
+        sequence: u64,
+        ack: String,
+        success: bool,
+    },
+    IbcTimeout {
+        channel: String,
+        sequence: u64,
+    },
 }
 
 #[cw_serde]
@@ -1,26 +1,40 @@
 use cosmwasm_std::{
-    coin, wasm_execute, CosmosMsg, Decimal, DepsMut, Env, Order, Reply, Response, SubMsgResult,
+    coin, wasm_execute, BankMsg, CosmosMsg, DepsMut, Env, Reply, Response, SubMsgResult, Uint128,
 };
-use osmosis_std::types::osmosis::concentratedliquidity::v1beta1::{
-    MsgCreatePositionResponse, MsgTransferPositions,
-};
-use zapper::{
-    asset::{get_current_asset_available, Asset},
-    error::ZapperError,
+use osmosis_std::types::{
+    ibc::applications::transfer::v1::MsgTransferResponse,
+    osmosis::concentratedliquidity::v1beta1::{
+        MsgAddToPositionResponse, MsgCreatePositionResponse, MsgTransferPositions,
+    },
 };
+use zapper::{asset::Asset, error::ZapperError, swap::Route};
 
 use crate::{
     error::{ContractError, ContractResult},
-    helper::{create_osmosis_swap_msg, create_refund_msg},
+    helper::{
+        compute_rebalance_swap, create_osmosis_swap_msg, create_refund_msg, deduct_protocol_fee,
+        deduct_referral_fee, query_concentrated_pool, rebalance_minimum_receive,
+    },
     msg::ExecuteMsg,
-    state::{ProtocolFee, PENDING_POSITION, PENDING_ZAP_OUT, PROTOCOL_FEE, SNAP_BALANCES},
+    state::{
+        clear_snapshot_balances, get_available_balance, load_snapshot_balances, FeeDirection,
+        IbcTransfer, PendingPosition, IBC_TRANSFERS, PENDING_ADD_TO_POSITIONS,
+        PENDING_IBC_TRANSFERS, PENDING_IBC_ZAP_INS, PENDING_JOIN_POOLS, PENDING_MIGRATIONS,
+        PENDING_POSITIONS, PENDING_ZAP_OUTS, POSITION_OWNERS, SNAP_BALANCES,
+    },
 };
 
-pub fn reply_create_position(deps: DepsMut, env: Env, msg: Reply) -> ContractResult<Response> {
+// Failed replies revert the whole operation, including its pending state and snapshots
+pub fn reply_create_position(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+    nonce: u64,
+) -> ContractResult<Response> {
     match msg.result.clone() {
         SubMsgResult::Ok(_) => {
             let msg_create_pos_res = MsgCreatePositionResponse::try_from(msg.result)?;
-            let pending_position = PENDING_POSITION.load(deps.storage)?;
+            let pending_position = PENDING_POSITIONS.load(deps.storage, nonce)?;
             // transfer position to receiver
             let mut msgs: Vec<CosmosMsg> = vec![];
             let position_id = msg_create_pos_res.position_id;
@@ -35,41 +49,236 @@ pub fn reply_create_position(deps: DepsMut, env: Env, msg: Reply) -> ContractRes
                 .into(),
             );
 
-            // Refund tokens
-            for token in [&pending_position.token_0, &pending_position.token_1].iter() {
+            // Refund every snapshotted token, inputs of a multi asset zap included
+            for (denom, _) in load_snapshot_balances(deps.storage, nonce)? {
                 if let Some(msg) =
-                    create_refund_msg(&deps.as_ref(), &env, token, &receiver_address)?
+                    create_refund_msg(&deps.as_ref(), &env, nonce, &denom, &receiver_address)?
                 {
                     msgs.push(msg);
                 }
             }
             // remove pending position & snapshot balances
-            PENDING_POSITION.remove(deps.storage);
-            SNAP_BALANCES.remove(deps.storage, &pending_position.token_0);
-            SNAP_BALANCES.remove(deps.storage, &pending_position.token_1);
+            PENDING_POSITIONS.remove(deps.storage, nonce);
+            clear_snapshot_balances(deps.storage, nonce)?;
+
+            Ok(Response::new().add_messages(msgs))
+        }
+        SubMsgResult::Err(e) => Err(ContractError::CreatePositionError(e)),
+    }
+}
+
+pub fn reply_add_to_position(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+    nonce: u64,
+) -> ContractResult<Response> {
+    match msg.result.clone() {
+        SubMsgResult::Ok(_) => {
+            let msg_add_to_pos_res = MsgAddToPositionResponse::try_from(msg.result)?;
+            let pending = PENDING_ADD_TO_POSITIONS.load(deps.storage, nonce)?;
+            let receiver_address = pending.receiver.to_string();
+            let mut msgs: Vec<CosmosMsg> = vec![];
+
+            // the position is replaced by a new one, transfer it to receiver
+            msgs.push(
+                MsgTransferPositions {
+                    position_ids: vec![msg_add_to_pos_res.position_id],
+                    sender: env.contract.address.to_string(),
+                    new_owner: receiver_address.clone(),
+                }
+                .into(),
+            );
+
+            // Refund unused tokens and claimed rewards
+            for (denom, _) in load_snapshot_balances(deps.storage, nonce)? {
+                if let Some(msg) =
+                    create_refund_msg(&deps.as_ref(), &env, nonce, &denom, &receiver_address)?
+                {
+                    msgs.push(msg);
+                }
+            }
+
+            // remove pending & snapshot balances, the old position no longer exists
+            PENDING_ADD_TO_POSITIONS.remove(deps.storage, nonce);
+            POSITION_OWNERS.remove(deps.storage, pending.position_id);
+            clear_snapshot_balances(deps.storage, nonce)?;
+
+            Ok(Response::new().add_messages(msgs))
+        }
+        SubMsgResult::Err(e) => Err(ContractError::AddToPositionError(e)),
+    }
+}
+
+pub fn reply_migrate_position(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+    nonce: u64,
+) -> ContractResult<Response> {
+    match msg.result {
+        SubMsgResult::Ok(_) => {
+            let pending_migration = PENDING_MIGRATIONS.load(deps.storage, nonce)?;
+            let mut msgs: Vec<CosmosMsg> = vec![];
+
+            // what the withdraw returned, including rewards in the position tokens
+            let mut withdrawn: Vec<Uint128> = vec![];
+            for denom in [&pending_migration.token_0, &pending_migration.token_1] {
+                let balance_before = SNAP_BALANCES.load(deps.storage, (nonce, denom))?;
+                let balance = get_available_balance(
+                    deps.api,
+                    &deps.querier,
+                    deps.storage,
+                    &env.contract.address,
+                    denom,
+                )?;
+                withdrawn.push(balance.amount().checked_sub(balance_before)?);
+            }
+
+            // swap only the surplus side into the other token, at the current pool price
+            let pool = query_concentrated_pool(&deps.as_ref(), pending_migration.pool_id)?;
+            let surplus = compute_rebalance_swap(
+                &pool,
+                pending_migration.lower_tick,
+                pending_migration.upper_tick,
+                withdrawn[0],
+                withdrawn[1],
+            )?;
+            if let Some(surplus) = surplus {
+                // without a path from the surplus token the leftover is refunded
+                let path = pending_migration.paths.iter().find(|path| {
+                    path.first().map(|op| op.denom_in.as_str()) == Some(surplus.denom.as_str())
+                });
+                if let Some(path) = path {
+                    let asset: Asset = surplus.clone().into();
+                    let amount_to_swap = deduct_protocol_fee(
+                        deps.storage,
+                        pending_migration.pool_id,
+                        FeeDirection::ZapOut,
+                        &asset,
+                        surplus.amount,
+                    )?;
+
+                    let coin_in = coin(amount_to_swap.into(), surplus.denom);
+                    let minimum_receive =
+                        rebalance_minimum_receive(&pool, &coin_in, pending_migration.max_slippage)?;
+
+                    let swap_msg = create_osmosis_swap_msg(
+                        env.contract.address.to_string(),
+                        coin_in,
+                        path.clone(),
+                        Some(minimum_receive).filter(|amount| !amount.is_zero()),
+                    )?;
+                    msgs.push(swap_msg);
+                }
+            }
+
+            // create the new position with the same nonce, its reply refunds the leftovers
+            msgs.push(
+                wasm_execute(
+                    env.contract.address.to_string(),
+                    &ExecuteMsg::CreatePosition {
+                        pool_id: pending_migration.pool_id,
+                        token_0: pending_migration.token_0.clone(),
+                        token_1: pending_migration.token_1.clone(),
+                        lower_tick: pending_migration.lower_tick,
+                        upper_tick: pending_migration.upper_tick,
+                        token_min_amount_0: pending_migration.token_min_amount_0,
+                        token_min_amount_1: pending_migration.token_min_amount_1,
+                        nonce,
+                    },
+                    vec![],
+                )?
+                .into(),
+            );
+            PENDING_POSITIONS.save(
+                deps.storage,
+                nonce,
+                &PendingPosition {
+                    receiver: pending_migration.receiver,
+                    pool_id: pending_migration.pool_id,
+                    token_0: pending_migration.token_0,
+                    token_1: pending_migration.token_1,
+                },
+            )?;
+
+            // remove pending migration, the old position no longer exists
+            PENDING_MIGRATIONS.remove(deps.storage, nonce);
+            POSITION_OWNERS.remove(deps.storage, pending_migration.position_id);
 
             Ok(Response::new().add_messages(msgs))
         }
-        SubMsgResult::Err(e) => return Err(ContractError::CreatePositionError(e)),
+        SubMsgResult::Err(e) => Err(ContractError::WithdrawPositionError(e)),
+    }
+}
+
+// The zap reverts on failure, so the received funds are back in the contract
+pub fn reply_ibc_zap_in(deps: DepsMut, msg: Reply, nonce: u64) -> ContractResult<Response> {
+    let pending_ibc_zap_in = PENDING_IBC_ZAP_INS.load(deps.storage, nonce)?;
+    PENDING_IBC_ZAP_INS.remove(deps.storage, nonce);
+
+    match msg.result {
+        SubMsgResult::Ok(_) => Ok(Response::new()),
+        SubMsgResult::Err(e) => Ok(Response::new()
+            .add_message(BankMsg::Send {
+                to_address: pending_ibc_zap_in.recovery_address.to_string(),
+                amount: pending_ibc_zap_in.funds,
+            })
+            .add_attributes(vec![
+                ("action", "ibc_zap_in_recovery"),
+                (
+                    "recovery_address",
+                    pending_ibc_zap_in.recovery_address.as_str(),
+                ),
+                ("error", &e),
+            ])),
     }
 }
 
-pub fn reply_withdraw_position(deps: DepsMut, env: Env, msg: Reply) -> ContractResult<Response> {
+// Records the sequence of each ibc transfer, replies come in the order of the transfers
+pub fn reply_ibc_transfer(deps: DepsMut, msg: Reply, nonce: u64) -> ContractResult<Response> {
+    let msg_transfer_res = MsgTransferResponse::try_from(msg.result)?;
+    let mut pending_ibc_transfer = PENDING_IBC_TRANSFERS.load(deps.storage, nonce)?;
+    let coin = pending_ibc_transfer.coins.remove(0);
+
+    IBC_TRANSFERS.save(
+        deps.storage,
+        (&pending_ibc_transfer.channel, msg_transfer_res.sequence),
+        &IbcTransfer {
+            receiver: pending_ibc_transfer.receiver.clone(),
+            coin,
+        },
+    )?;
+
+    if pending_ibc_transfer.coins.is_empty() {
+        PENDING_IBC_TRANSFERS.remove(deps.storage, nonce);
+    } else {
+        PENDING_IBC_TRANSFERS.save(deps.storage, nonce, &pending_ibc_transfer)?;
+    }
+
+    Ok(Response::new())
+}
+
+pub fn reply_withdraw_position(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+    nonce: u64,
+) -> ContractResult<Response> {
     match msg.result.clone() {
         SubMsgResult::Ok(_) => {
-            let pending_zap_out = PENDING_ZAP_OUT.load(deps.storage)?;
+            let pending_zap_out = PENDING_ZAP_OUTS.load(deps.storage, nonce)?;
             // transfer position to receiver
             let mut msgs: Vec<CosmosMsg> = vec![];
 
             // no need to use hashMap because the number of tokens is very small
-            let mut all_balances: Vec<Asset> = SNAP_BALANCES
-                .range(deps.storage, None, None, Order::Ascending)
-                .map(|item| {
-                    let (denom, amount) = item?;
-
-                    let current_balance = get_current_asset_available(
+            let mut all_balances: Vec<Asset> = load_snapshot_balances(deps.storage, nonce)?
+                .into_iter()
+                .map(|(denom, amount)| {
+                    let current_balance = get_available_balance(
                         deps.api,
                         &deps.querier,
+                        deps.storage,
                         &env.contract.address,
                         &denom,
                     )?;
@@ -82,13 +291,29 @@ pub fn reply_withdraw_position(deps: DepsMut, env: Env, msg: Reply) -> ContractR
                 })
                 .collect::<ContractResult<Vec<Asset>>>()?;
 
-            let protocol_fee = PROTOCOL_FEE.may_load(deps.storage)?.unwrap_or(ProtocolFee {
-                percent: Decimal::zero(),
-                fee_receiver: pending_zap_out.receiver.clone(),
-            });
+            // swap every received balance into the target along the path starting at its denom,
+            // balances without a path are refunded as is
+            let routes = match pending_zap_out.target {
+                Some(target) => all_balances
+                    .iter()
+                    .filter(|b| b.denom() != target.denom && !b.amount().is_zero())
+                    .filter_map(|b| {
+                        let path = target.paths.iter().find(|path| {
+                            path.first().map(|op| op.denom_in.as_str()) == Some(b.denom())
+                        })?;
+                        Some(Route {
+                            token_in: b.denom().to_string(),
+                            offer_amount: b.amount(),
+                            operations: path.clone(),
+                            minimum_receive: None,
+                        })
+                    })
+                    .collect(),
+                None => pending_zap_out.routes,
+            };
 
             // try swaps
-            for route in pending_zap_out.routes {
+            for route in routes {
                 if let Some(balance) = all_balances
                     .iter_mut()
                     .find(|b| b.denom().eq(&route.token_in))
@@ -100,16 +325,20 @@ pub fn reply_withdraw_position(deps: DepsMut, env: Env, msg: Reply) -> ContractR
                     }
                     balance.sub(route.offer_amount)?;
 
-                    let mut amount_to_swap = route.offer_amount;
-                    if !protocol_fee.percent.is_zero() {
-                        let fee_amount = amount_to_swap * protocol_fee.percent;
-                        amount_to_swap -= fee_amount;
-
-                        // transfer fee to fee_receiver
-                        msgs.push(
-                            balance.transfer_amount(fee_amount, protocol_fee.fee_receiver.as_str()),
-                        );
-                    }
+                    let amount_to_swap = deduct_protocol_fee(
+                        deps.storage,
+                        pending_zap_out.pool_id,
+                        FeeDirection::ZapOut,
+                        balance,
+                        route.offer_amount,
+                    )?;
+                    let amount_to_swap = deduct_referral_fee(
+                        deps.storage,
+                        pending_zap_out.referral.as_ref(),
+                        balance,
+                        amount_to_swap,
+                        &mut msgs,
+                    )?;
 
                     let swap_msg = create_osmosis_swap_msg(
                         env.contract.address.to_string(),
@@ -117,26 +346,75 @@ pub fn reply_withdraw_position(deps: DepsMut, env: Env, msg: Reply) -> ContractR
                         route.operations,
                         route.minimum_receive,
                     )?;
-                    msgs.push(swap_msg.into());
+                    msgs.push(swap_msg);
                 }
             }
 
+            // hand the remaining position back to the receiver
+            if let Some(position_id) = pending_zap_out.residual_position_id {
+                msgs.push(
+                    MsgTransferPositions {
+                        position_ids: vec![position_id],
+                        sender: env.contract.address.to_string(),
+                        new_owner: pending_zap_out.receiver.to_string(),
+                    }
+                    .into(),
+                );
+            }
+
             // transfer fund back
             msgs.push(
                 wasm_execute(
                     env.contract.address.to_string(),
                     &ExecuteMsg::TransferFundsBack {
                         receiver: pending_zap_out.receiver,
+                        nonce,
+                        minimum_receive: pending_zap_out.minimum_receive,
+                        ibc_return: pending_zap_out.ibc_return,
                     },
                     vec![],
                 )?
                 .into(),
             );
-            // remove pending & snapshot balances
-            PENDING_ZAP_OUT.remove(deps.storage);
+            // remove pending & snapshot balances, the position leaves the escrow
+            PENDING_ZAP_OUTS.remove(deps.storage, nonce);
+            if let Some(position_id) = pending_zap_out.position_id {
+                POSITION_OWNERS.remove(deps.storage, position_id);
+            }
+
+            Ok(Response::new().add_messages(msgs))
+        }
+        SubMsgResult::Err(e) => Err(ContractError::WithdrawPositionError(e)),
+    }
+}
+
+pub fn reply_join_pool(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+    nonce: u64,
+) -> ContractResult<Response> {
+    match msg.result {
+        SubMsgResult::Ok(_) => {
+            let pending_join_pool = PENDING_JOIN_POOLS.load(deps.storage, nonce)?;
+            let receiver_address = pending_join_pool.receiver.to_string();
+            let mut msgs: Vec<CosmosMsg> = vec![];
+
+            // transfer LP shares and refund leftover pool tokens and sent asset
+            for (denom, _) in load_snapshot_balances(deps.storage, nonce)? {
+                if let Some(msg) =
+                    create_refund_msg(&deps.as_ref(), &env, nonce, &denom, &receiver_address)?
+                {
+                    msgs.push(msg);
+                }
+            }
+
+            // remove pending join pool & snapshot balances
+            PENDING_JOIN_POOLS.remove(deps.storage, nonce);
+            clear_snapshot_balances(deps.storage, nonce)?;
 
             Ok(Response::new().add_messages(msgs))
         }
-        SubMsgResult::Err(e) => return Err(ContractError::WithdrawPositionError(e)),
+        SubMsgResult::Err(e) => Err(ContractError::JoinPoolError(e)),
     }
 }
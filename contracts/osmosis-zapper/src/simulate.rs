@@ -0,0 +1,295 @@
+use std::str::FromStr;
+
+use cosmwasm_std::{coin, Coin, Decimal, Decimal256, Deps, StdError, Uint128};
+use osmosis_std::types::osmosis::concentratedliquidity::v1beta1::ConcentratedliquidityQuerier;
+use zapper::{
+    asset::Asset,
+    error::ZapperError,
+    swap::Route,
+    tick_math::{
+        amounts_for_liquidity, liquidity_for_amounts, parse_osmosis_decimal, tick_to_sqrt_price,
+    },
+};
+
+use crate::{
+    error::{ContractError, ContractResult},
+    helper::{
+        build_optimal_swap_routes, estimate_swap_exact_amount_in, query_concentrated_pool,
+        resolve_withdraw_liquidity, validate_referral, validate_zap_in_routes,
+    },
+    msg::{RouteSimulation, SimulateZapInResponse, SimulateZapOutResponse},
+    state::{protocol_fee_percent, FeeDirection, Referral},
+};
+
+fn add_coin(coins: &mut Vec<Coin>, denom: &str, amount: Uint128) {
+    if amount.is_zero() {
+        return;
+    }
+    match coins.iter_mut().find(|c| c.denom == denom) {
+        Some(c) => c.amount += amount,
+        None => coins.push(coin(amount.u128(), denom)),
+    }
+}
+
+fn amount_of(coins: &[Coin], denom: &str) -> Uint128 {
+    coins
+        .iter()
+        .find(|c| c.denom == denom)
+        .map(|c| c.amount)
+        .unwrap_or_default()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_zap_in(
+    deps: Deps,
+    asset_in: Asset,
+    pool_id: u64,
+    token_0: String,
+    token_1: String,
+    lower_tick: i64,
+    upper_tick: i64,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    routes: Vec<Route>,
+    optimal_swap: Option<bool>,
+    referral: Option<Referral>,
+) -> ContractResult<SimulateZapInResponse> {
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    let fee_amount =
+        asset_in.amount() * protocol_fee_percent(deps.storage, pool_id, FeeDirection::ZapIn)?;
+    let amount_after_fee = asset_in.amount().checked_sub(fee_amount)?;
+    let referral_fee_amount = referral
+        .as_ref()
+        .map(|referral| referral.fee_amount(amount_after_fee))
+        .unwrap_or_default();
+    let amount_after_fee = amount_after_fee.checked_sub(referral_fee_amount)?;
+
+    validate_zap_in_routes(&routes, asset_in.denom())?;
+
+    let routes = if optimal_swap.unwrap_or(false) {
+        build_optimal_swap_routes(
+            &deps,
+            pool_id,
+            &token_0,
+            &token_1,
+            lower_tick,
+            upper_tick,
+            asset_in.denom(),
+            amount_after_fee,
+            &routes,
+        )?
+    } else {
+        routes
+    };
+
+    let total_swap_amount: Uint128 = routes.iter().map(|route| route.offer_amount).sum();
+    if total_swap_amount.gt(&amount_after_fee) {
+        return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+    }
+
+    // what is left of the input is provided as is
+    let mut balances: Vec<Coin> = vec![];
+    add_coin(
+        &mut balances,
+        asset_in.denom(),
+        amount_after_fee - total_swap_amount,
+    );
+
+    let mut route_simulations: Vec<RouteSimulation> = vec![];
+    for route in routes {
+        let token_out = route.ask_denom()?;
+        let return_amount = estimate_swap_exact_amount_in(
+            &deps,
+            coin(route.offer_amount.u128(), asset_in.denom()),
+            route.operations,
+        )?;
+        if return_amount < route.minimum_receive.unwrap_or_default() {
+            return Err(ContractError::MinimumReceiveNotMet {
+                denom: token_out,
+                expected: route.minimum_receive.unwrap_or_default(),
+                received: return_amount,
+            });
+        }
+        add_coin(&mut balances, &token_out, return_amount);
+        route_simulations.push(RouteSimulation {
+            token_in: asset_in.denom().to_string(),
+            offer_amount: route.offer_amount,
+            token_out,
+            return_amount,
+        });
+    }
+
+    let amount_0 = amount_of(&balances, &token_0);
+    let amount_1 = amount_of(&balances, &token_1);
+    let mut tokens_provided: Vec<Coin> = vec![];
+    add_coin(&mut tokens_provided, &token_0, amount_0);
+    add_coin(&mut tokens_provided, &token_1, amount_1);
+
+    let pool = query_concentrated_pool(&deps, pool_id)?;
+    let current_sqrt_price = parse_osmosis_decimal(&pool.current_sqrt_price)?;
+    let lower_sqrt_price = tick_to_sqrt_price(lower_tick)?;
+    let upper_sqrt_price = tick_to_sqrt_price(upper_tick)?;
+
+    let liquidity = liquidity_for_amounts(
+        current_sqrt_price,
+        lower_sqrt_price,
+        upper_sqrt_price,
+        amount_0,
+        amount_1,
+    )?;
+    let (used_0, used_1) = amounts_for_liquidity(
+        current_sqrt_price,
+        lower_sqrt_price,
+        upper_sqrt_price,
+        liquidity,
+    )?;
+    let used_0 = used_0.min(amount_0);
+    let used_1 = used_1.min(amount_1);
+
+    if used_0 < token_min_amount_0.unwrap_or_default()
+        || used_1 < token_min_amount_1.unwrap_or_default()
+    {
+        return Err(ContractError::CreatePositionError(
+            "insufficient amount of tokens used".to_string(),
+        ));
+    }
+
+    // what the routes leave of an input that is not a position token is refunded as is
+    let mut refunds: Vec<Coin> = vec![];
+    add_coin(&mut refunds, &token_0, amount_0 - used_0);
+    add_coin(&mut refunds, &token_1, amount_1 - used_1);
+    if asset_in.denom() != token_0 && asset_in.denom() != token_1 {
+        add_coin(
+            &mut refunds,
+            asset_in.denom(),
+            amount_of(&balances, asset_in.denom()),
+        );
+    }
+
+    Ok(SimulateZapInResponse {
+        protocol_fee: coin(fee_amount.u128(), asset_in.denom()),
+        referral_fee: coin(referral_fee_amount.u128(), asset_in.denom()),
+        routes: route_simulations,
+        tokens_provided,
+        liquidity,
+        refunds,
+    })
+}
+
+pub fn simulate_zap_out(
+    deps: Deps,
+    position_id: u64,
+    routes: Vec<Route>,
+    liquidity_amount: Option<Decimal256>,
+    percent: Option<Decimal>,
+    minimum_receive: Option<Vec<Coin>>,
+    referral: Option<Referral>,
+) -> ContractResult<SimulateZapOutResponse> {
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let position = position_detail
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+
+    let position_liquidity = Decimal256::from_str(&position.liquidity)?;
+    let withdraw_liquidity =
+        resolve_withdraw_liquidity(position_liquidity, liquidity_amount, percent)?;
+    let withdraw_ratio =
+        Decimal::try_from(withdraw_liquidity / position_liquidity).map_err(ZapperError::from)?;
+
+    // principal is withdrawn pro rata, rewards are claimed in full
+    let mut withdrawn: Vec<Coin> = vec![];
+    for asset in [&position_detail.asset0, &position_detail.asset1]
+        .into_iter()
+        .flatten()
+    {
+        add_coin(
+            &mut withdrawn,
+            &asset.denom,
+            Uint128::from_str(&asset.amount)? * withdraw_ratio,
+        );
+    }
+    for reward in position_detail
+        .claimable_spread_rewards
+        .iter()
+        .chain(position_detail.claimable_incentives.iter())
+    {
+        add_coin(
+            &mut withdrawn,
+            &reward.denom,
+            Uint128::from_str(&reward.amount)?,
+        );
+    }
+
+    let fee_percent = protocol_fee_percent(deps.storage, position.pool_id, FeeDirection::ZapOut)?;
+
+    let mut balances = withdrawn.clone();
+    let mut protocol_fees: Vec<Coin> = vec![];
+    let mut referral_fees: Vec<Coin> = vec![];
+    let mut route_simulations: Vec<RouteSimulation> = vec![];
+    let mut outputs: Vec<Coin> = vec![];
+    for route in routes {
+        let Some(balance) = balances.iter_mut().find(|b| b.denom == route.token_in) else {
+            continue;
+        };
+        if balance.amount < route.offer_amount {
+            return Err(ContractError::Zapper(
+                ZapperError::ZapOutNotEnoughBalanceToSwap {},
+            ));
+        }
+        balance.amount -= route.offer_amount;
+
+        let fee_amount = route.offer_amount * fee_percent;
+        add_coin(&mut protocol_fees, &route.token_in, fee_amount);
+
+        let token_out = route.ask_denom()?;
+        let amount_to_swap = route.offer_amount.checked_sub(fee_amount)?;
+        let referral_fee_amount = referral
+            .as_ref()
+            .map(|referral| referral.fee_amount(amount_to_swap))
+            .unwrap_or_default();
+        add_coin(&mut referral_fees, &route.token_in, referral_fee_amount);
+        let amount_to_swap = amount_to_swap.checked_sub(referral_fee_amount)?;
+        let return_amount = estimate_swap_exact_amount_in(
+            &deps,
+            coin(amount_to_swap.u128(), &route.token_in),
+            route.operations,
+        )?;
+        add_coin(&mut outputs, &token_out, return_amount);
+        route_simulations.push(RouteSimulation {
+            token_in: route.token_in,
+            offer_amount: route.offer_amount,
+            token_out,
+            return_amount,
+        });
+    }
+
+    let mut refunds: Vec<Coin> = vec![];
+    for c in balances.iter().chain(outputs.iter()) {
+        add_coin(&mut refunds, &c.denom, c.amount);
+    }
+
+    for min in minimum_receive.unwrap_or_default() {
+        let received = amount_of(&refunds, &min.denom);
+        if received < min.amount {
+            return Err(ContractError::MinimumReceiveNotMet {
+                denom: min.denom,
+                expected: min.amount,
+                received,
+            });
+        }
+    }
+
+    Ok(SimulateZapOutResponse {
+        protocol_fees,
+        referral_fees,
+        withdrawn,
+        routes: route_simulations,
+        refunds,
+    })
+}
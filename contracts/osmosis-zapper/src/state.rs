@@ -1,22 +1,146 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Api, Decimal, Env, QuerierWrapper, Storage, Uint128};
+use cosmwasm_std::{
+    Addr, Api, Coin, Decimal, Env, Order, QuerierWrapper, StdResult, Storage, Uint128,
+};
 use cw_controllers::Admin;
 use cw_storage_plus::{Item, Map};
-use zapper::{asset::get_current_asset_available, swap::Route};
+use cw_utils::Expiration;
+use zapper::{
+    asset::{get_current_asset_available, Asset},
+    swap::{Route, SwapOperation},
+};
 
 use crate::error::ContractResult;
 
 pub const OWNER: Admin = Admin::new("owner");
+pub const PENDING_OWNER: Item<PendingOwner> = Item::new("pending_owner");
 
 pub const PROTOCOL_FEE: Item<ProtocolFee> = Item::new("protocol_fee");
-pub const SNAP_BALANCES: Map<&str, Uint128> = Map::new("snap_balances");
-pub const PENDING_POSITION: Item<PendingPosition> = Item::new("pending_position");
-pub const PENDING_ZAP_OUT: Item<PendingZapOut> = Item::new("pending_zap_out");
+// fee percent overrides of PROTOCOL_FEE keyed by (pool id, fee direction)
+pub const POOL_FEES: Map<(u64, &str), Decimal> = Map::new("pool_fees");
+// cap on the referral fee a zap can carry, referrals are rejected until it is set
+pub const MAX_REFERRAL_BPS: Item<u16> = Item::new("max_referral_bps");
+// total referral fees paid keyed by (referrer, denom)
+pub const REFERRAL_FEES: Map<(&Addr, &str), Uint128> = Map::new("referral_fees");
+// protocol fees held by the contract per denom until they are claimed
+pub const ACCRUED_FEES: Map<&str, Uint128> = Map::new("accrued_fees");
+// denom the accrued fees are converted into
+pub const TREASURY_DENOM: Item<String> = Item::new("treasury_denom");
+// pending operations and their balance snapshots are keyed by the operation nonce,
+// which is carried through the reply id so that concurrent zaps never share state
+pub const NONCE: Item<u64> = Item::new("nonce");
+pub const SNAP_BALANCES: Map<(u64, &str), Uint128> = Map::new("snapshot_balances");
+pub const PENDING_POSITIONS: Map<u64, PendingPosition> = Map::new("pending_positions");
+pub const PENDING_ZAP_OUTS: Map<u64, PendingZapOut> = Map::new("pending_zap_outs");
+pub const PENDING_JOIN_POOLS: Map<u64, PendingJoinPool> = Map::new("pending_join_pools");
+pub const PENDING_MIGRATIONS: Map<u64, PendingMigration> = Map::new("pending_migrations");
+pub const PENDING_ADD_TO_POSITIONS: Map<u64, PendingAddToPosition> =
+    Map::new("pending_add_to_positions");
+pub const PENDING_IBC_ZAP_INS: Map<u64, PendingIbcZapIn> = Map::new("pending_ibc_zap_ins");
+pub const PENDING_IBC_TRANSFERS: Map<u64, PendingIbcTransfer> = Map::new("pending_ibc_transfers");
+// ibc transfers waiting for their ack, keyed by (source channel, sequence)
+pub const IBC_TRANSFERS: Map<(&str, u64), IbcTransfer> = Map::new("ibc_transfers");
+// converter of each accepted cw20 into its native counterpart
+pub const CW20_CONVERTERS: Map<&Addr, Cw20Converter> = Map::new("cw20_converters");
+// depositor of each position escrowed in the contract
+pub const POSITION_OWNERS: Map<u64, Addr> = Map::new("position_owners");
+
+#[cw_serde]
+pub struct PendingIbcZapIn {
+    pub recovery_address: Addr,
+    pub funds: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct Cw20Converter {
+    pub contract: Addr,
+    pub native_denom: String,
+}
+
+#[cw_serde]
+#[derive(Copy)]
+pub enum FeeDirection {
+    ZapIn,
+    ZapOut,
+}
+
+impl FeeDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FeeDirection::ZapIn => "zap_in",
+            FeeDirection::ZapOut => "zap_out",
+        }
+    }
+}
+
+// Fee percent of the pool in the given direction, falling back to the protocol fee
+pub fn protocol_fee_percent(
+    storage: &dyn Storage,
+    pool_id: u64,
+    direction: FeeDirection,
+) -> StdResult<Decimal> {
+    if let Some(percent) = POOL_FEES.may_load(storage, (pool_id, direction.as_str()))? {
+        return Ok(percent);
+    }
+    Ok(PROTOCOL_FEE
+        .may_load(storage)?
+        .map(|protocol_fee| protocol_fee.percent)
+        .unwrap_or_default())
+}
+
+#[cw_serde]
+pub struct PendingOwner {
+    pub new_owner: Addr,
+    pub expiry: Option<Expiration>,
+}
 
 #[cw_serde]
 pub struct ProtocolFee {
     pub percent: Decimal,
+    // receives the whole fee when fee_receivers is empty, otherwise the rounding dust
     pub fee_receiver: Addr,
+    #[serde(default)]
+    pub fee_receivers: Vec<FeeReceiver>,
+}
+
+impl ProtocolFee {
+    // Splits the fee amount across the weighted receivers, the rounding dust goes to fee_receiver
+    pub fn split(&self, amount: Uint128) -> Vec<(Addr, Uint128)> {
+        let total_weight: u128 = self.fee_receivers.iter().map(|r| r.weight as u128).sum();
+        let mut shares: Vec<(Addr, Uint128)> = vec![];
+        let mut remaining = amount;
+        if total_weight > 0 {
+            for receiver in &self.fee_receivers {
+                let share = amount.multiply_ratio(receiver.weight, total_weight);
+                if !share.is_zero() {
+                    remaining -= share;
+                    shares.push((receiver.address.clone(), share));
+                }
+            }
+        }
+        if !remaining.is_zero() {
+            shares.push((self.fee_receiver.clone(), remaining));
+        }
+        shares
+    }
+}
+
+#[cw_serde]
+pub struct Referral {
+    pub address: Addr,
+    pub bps: u16,
+}
+
+impl Referral {
+    pub fn fee_amount(&self, amount: Uint128) -> Uint128 {
+        amount.multiply_ratio(self.bps, 10_000u128)
+    }
+}
+
+#[cw_serde]
+pub struct FeeReceiver {
+    pub address: Addr,
+    pub weight: u64,
 }
 
 #[cw_serde]
@@ -27,10 +151,103 @@ pub struct PendingPosition {
     pub token_1: String,
 }
 
+#[cw_serde]
+pub struct PendingAddToPosition {
+    pub receiver: Addr,
+    pub position_id: u64,
+}
+
+#[cw_serde]
+pub struct PendingJoinPool {
+    pub receiver: Addr,
+    pub pool_id: u64,
+    pub denoms: Vec<String>,
+}
+
 #[cw_serde]
 pub struct PendingZapOut {
     pub receiver: Addr,
+    // pool of the position or the LP shares, selects the zap out fee
+    pub pool_id: u64,
     pub routes: Vec<Route>,
+    // when set, everything received is swapped into the target instead of following routes
+    pub target: Option<ZapOutTarget>,
+    // checked against the total refunds once all swaps are done
+    pub minimum_receive: Vec<Coin>,
+    // CL position being withdrawn, none when exiting a GAMM pool
+    pub position_id: Option<u64>,
+    // set when only part of the liquidity is withdrawn, the position is handed back to the receiver
+    pub residual_position_id: Option<u64>,
+    // when set, the proceeds are sent to a remote chain
+    pub ibc_return: Option<IbcReturn>,
+    pub referral: Option<Referral>,
+}
+
+#[cw_serde]
+pub struct PendingMigration {
+    pub receiver: Addr,
+    pub position_id: u64,
+    pub pool_id: u64,
+    pub token_0: String,
+    pub token_1: String,
+    pub lower_tick: i64,
+    pub upper_tick: i64,
+    pub token_min_amount_0: Option<Uint128>,
+    pub token_min_amount_1: Option<Uint128>,
+    // the path starting at the surplus token rebalances the withdrawn tokens
+    pub paths: Vec<Vec<SwapOperation>>,
+    pub max_slippage: Decimal,
+}
+
+#[cw_serde]
+pub struct IbcReturn {
+    pub channel: String,
+    pub receiver: String,
+    // seconds after the zap out block at which the transfer times out
+    pub timeout: u64,
+    pub memo: Option<String>,
+}
+
+#[cw_serde]
+pub struct PendingIbcTransfer {
+    pub receiver: Addr,
+    pub channel: String,
+    // coins still waiting for the sequence of their transfer, in transfer order
+    pub coins: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct IbcTransfer {
+    // local address refunded when the transfer fails
+    pub receiver: Addr,
+    pub coin: Coin,
+}
+
+#[cw_serde]
+pub struct ZapOutTarget {
+    pub denom: String,
+    pub paths: Vec<Vec<SwapOperation>>,
+}
+
+pub fn next_nonce(storage: &mut dyn Storage) -> StdResult<u64> {
+    let nonce = NONCE.may_load(storage)?.unwrap_or_default() + 1;
+    NONCE.save(storage, &nonce)?;
+    Ok(nonce)
+}
+
+// Balance of the contract without the accrued protocol fees
+pub fn get_available_balance(
+    api: &dyn Api,
+    querier: &QuerierWrapper,
+    storage: &dyn Storage,
+    address: &Addr,
+    denom: &str,
+) -> ContractResult<Asset> {
+    let mut balance = get_current_asset_available(api, querier, address, denom)?;
+    if let Some(accrued) = ACCRUED_FEES.may_load(storage, denom)? {
+        balance.sub(accrued)?;
+    }
+    Ok(balance)
 }
 
 pub fn snapshot_balances(
@@ -38,9 +255,27 @@ pub fn snapshot_balances(
     querier: &QuerierWrapper,
     storage: &mut dyn Storage,
     env: &Env,
+    nonce: u64,
     denom: &str,
 ) -> ContractResult<()> {
-    let balance = get_current_asset_available(api, querier, &env.contract.address, denom)?;
-    SNAP_BALANCES.save(storage, &denom, &balance.amount())?;
+    let balance = get_available_balance(api, querier, storage, &env.contract.address, denom)?;
+    SNAP_BALANCES.save(storage, (nonce, denom), &balance.amount())?;
+    Ok(())
+}
+
+pub fn load_snapshot_balances(
+    storage: &dyn Storage,
+    nonce: u64,
+) -> StdResult<Vec<(String, Uint128)>> {
+    SNAP_BALANCES
+        .prefix(nonce)
+        .range(storage, None, None, Order::Ascending)
+        .collect()
+}
+
+pub fn clear_snapshot_balances(storage: &mut dyn Storage, nonce: u64) -> StdResult<()> {
+    for (denom, _) in load_snapshot_balances(storage, nonce)? {
+        SNAP_BALANCES.remove(storage, (nonce, &denom));
+    }
     Ok(())
 }
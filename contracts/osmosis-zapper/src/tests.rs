@@ -0,0 +1,2832 @@
+use std::{marker::PhantomData, str::FromStr};
+
+use cosmwasm_std::{
+    coin, from_json,
+    testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR},
+    to_json_binary, Addr, BalanceResponse as BankBalanceResponse, BankMsg, BankQuery, Binary, Coin,
+    CosmosMsg, Decimal, Empty, Order, OwnedDeps, Querier, QuerierResult, QueryRequest, Reply,
+    Response, StdError, StdResult, SubMsgResponse, SubMsgResult, SystemError, SystemResult,
+    Uint128, WasmMsg, WasmQuery,
+};
+use cw20::{
+    BalanceResponse as Cw20BalanceResponse, Cw20Coin, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg,
+};
+use cw_utils::Expiration;
+use osmosis_std::types::{
+    cosmos::base::v1beta1::Coin as ProtoCoin,
+    ibc::applications::transfer::v1::MsgTransferResponse,
+    osmosis::{
+        concentratedliquidity::v1beta1::{
+            FullPositionBreakdown, MsgAddToPosition, MsgAddToPositionResponse, MsgCreatePosition,
+            MsgCreatePositionResponse, MsgTransferPositions, MsgWithdrawPosition,
+            Pool as ConcentratedPool, Position, PositionByIdRequest, PositionByIdResponse,
+        },
+        gamm::v1beta1::{
+            MsgExitPool, MsgJoinPool, MsgJoinSwapExternAmountIn, MsgSwapExactAmountIn,
+            Pool as BalancerPool, PoolAsset, QueryCalcJoinPoolNoSwapSharesRequest,
+            QueryCalcJoinPoolNoSwapSharesResponse,
+        },
+        poolmanager::v1beta1::{
+            EstimateSwapExactAmountInRequest, EstimateSwapExactAmountInResponse, PoolRequest,
+            PoolResponse, TotalPoolLiquidityRequest, TotalPoolLiquidityResponse,
+        },
+    },
+};
+use zapper::{
+    asset::Asset,
+    swap::{Route, SwapOperation},
+};
+
+use crate::{
+    contract::{
+        encode_reply_id, execute, instantiate, query, reply, sudo, ADD_TO_POSITION_ID,
+        CREATE_POSITION_ID, EXIT_POOL_ID, HARVEST_REWARDS_ID, IBC_TRANSFER_ID, IBC_ZAP_IN_ID,
+        JOIN_POOL_ID, MIGRATE_POSITION_ID, WITHDRAW_POSITION_ID,
+    },
+    error::ContractError,
+    msg::{
+        Cw20HookMsg, EffectiveFeeResponse, ExecuteMsg, IbcLifecycleComplete, InstantiateMsg,
+        QueryMsg, RouteSimulation, SimulateZapInResponse, SimulateZapOutResponse, SudoMsg,
+    },
+    state::{
+        protocol_fee_percent, Cw20Converter, FeeDirection, FeeReceiver, IbcReturn, IbcTransfer,
+        ProtocolFee, Referral, ACCRUED_FEES, IBC_TRANSFERS, PENDING_IBC_TRANSFERS,
+        PENDING_IBC_ZAP_INS, PENDING_OWNER, POSITION_OWNERS, SNAP_BALANCES,
+    },
+};
+
+// MockApi accepts any lowercase string as an address, upper case denoms stay native
+const TOKEN_0: &str = "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2";
+const TOKEN_1: &str = "ibc/498A0751C798A0D9A389AA3691123DADA57DAA4FE165D5C75894505B876BA6E4";
+const REWARD: &str = "ibc/D189335C6E4A68B513C10AB227BF1C1D38C746766278BA3EEB4FB14124F1D858";
+const CW20: &str = "cw20token";
+const OWNER: &str = "owner";
+const USER: &str = "user";
+const POOL_ID: u64 = 1;
+const POSITION_ID: u64 = 7;
+const GAMM_POOL_ID: u64 = 3;
+
+// Answers the concentrated liquidity and GAMM stargate queries from fixed positions and pools
+struct ZapperQuerier {
+    base: MockQuerier,
+    positions: Vec<FullPositionBreakdown>,
+    pools: Vec<ConcentratedPool>,
+    gamm_pools: Vec<BalancerPool>,
+}
+
+impl Querier for ZapperQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<Empty> = match from_json(bin_request) {
+            Ok(request) => request,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: e.to_string(),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        match request {
+            QueryRequest::Stargate { path, data } => {
+                SystemResult::Ok(self.stargate_query(&path, data).into())
+            }
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg }) => {
+                SystemResult::Ok(self.cw20_balance_query(contract_addr, msg).into())
+            }
+            _ => self.base.raw_query(bin_request),
+        }
+    }
+}
+
+impl ZapperQuerier {
+    fn stargate_query(&self, path: &str, data: Binary) -> StdResult<Binary> {
+        match path {
+            "/osmosis.concentratedliquidity.v1beta1.Query/PositionById" => {
+                let request = PositionByIdRequest::try_from(data)?;
+                let position = self.positions.iter().find(|detail| {
+                    detail.position.as_ref().map(|p| p.position_id) == Some(request.position_id)
+                });
+                to_json_binary(&PositionByIdResponse {
+                    position: position.cloned(),
+                })
+            }
+            "/osmosis.poolmanager.v1beta1.Query/Pool" => {
+                let request = PoolRequest::try_from(data)?;
+                let pool = self.pools.iter().find(|pool| pool.id == request.pool_id);
+                let gamm_pool = self.gamm_pool(request.pool_id);
+                to_json_binary(&PoolResponse {
+                    pool: pool
+                        .map(|pool| pool.to_any())
+                        .or_else(|| gamm_pool.map(|pool| pool.to_any())),
+                })
+            }
+            "/osmosis.poolmanager.v1beta1.Query/TotalPoolLiquidity" => {
+                let request = TotalPoolLiquidityRequest::try_from(data)?;
+                to_json_binary(&TotalPoolLiquidityResponse {
+                    liquidity: self.gamm_liquidity(request.pool_id),
+                })
+            }
+            // every swap returns the offer less 1%
+            "/osmosis.poolmanager.v1beta1.Query/EstimateSwapExactAmountIn" => {
+                let request = EstimateSwapExactAmountInRequest::try_from(data)?;
+                let amount_in: String = request
+                    .token_in
+                    .chars()
+                    .take_while(char::is_ascii_digit)
+                    .collect();
+                to_json_binary(&EstimateSwapExactAmountInResponse {
+                    token_out_amount: (Uint128::from_str(&amount_in)? * Decimal::percent(99))
+                        .to_string(),
+                })
+            }
+            // shares are issued pro rata to the scarcest token, the rest is not used
+            "/osmosis.gamm.v1beta1.Query/CalcJoinPoolNoSwapShares" => {
+                let request = QueryCalcJoinPoolNoSwapSharesRequest::try_from(data)?;
+                let pool = self
+                    .gamm_pool(request.pool_id)
+                    .ok_or_else(|| StdError::not_found("pool"))?;
+                let total_shares = Uint128::from_str(&pool.total_shares.as_ref().unwrap().amount)?;
+                let liquidity = self.gamm_liquidity(request.pool_id);
+                let amount = |coin: &ProtoCoin| Uint128::from_str(&coin.amount).unwrap();
+                let join_ratio = request
+                    .tokens_in
+                    .iter()
+                    .map(|coin| {
+                        let reserve = liquidity.iter().find(|c| c.denom == coin.denom).unwrap();
+                        Decimal::from_ratio(amount(coin), amount(reserve))
+                    })
+                    .min()
+                    .unwrap_or_default();
+                to_json_binary(&QueryCalcJoinPoolNoSwapSharesResponse {
+                    tokens_out: liquidity
+                        .iter()
+                        .map(|c| proto_coin(&c.denom, (amount(c) * join_ratio).u128()))
+                        .collect(),
+                    shares_out: (total_shares * join_ratio).to_string(),
+                })
+            }
+            _ => Err(StdError::generic_err(format!("unsupported query {path}"))),
+        }
+    }
+
+    // MockApi takes the lower case GAMM share denom for a cw20, its balance is the bank one
+    fn cw20_balance_query(&self, denom: String, msg: Binary) -> StdResult<Binary> {
+        let Cw20QueryMsg::Balance { address } = from_json(msg)? else {
+            return Err(StdError::generic_err("unsupported cw20 query"));
+        };
+        let request = to_json_binary(&QueryRequest::<Empty>::Bank(BankQuery::Balance {
+            address,
+            denom,
+        }))?;
+        let balance: BankBalanceResponse =
+            from_json(self.base.raw_query(&request).unwrap().unwrap())?;
+        to_json_binary(&Cw20BalanceResponse {
+            balance: balance.amount.amount,
+        })
+    }
+
+    fn gamm_pool(&self, pool_id: u64) -> Option<&BalancerPool> {
+        self.gamm_pools.iter().find(|pool| pool.id == pool_id)
+    }
+
+    fn gamm_liquidity(&self, pool_id: u64) -> Vec<ProtoCoin> {
+        self.gamm_pool(pool_id)
+            .map(|pool| {
+                pool.pool_assets
+                    .iter()
+                    .filter_map(|asset| asset.token.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+type ZapperDeps = OwnedDeps<MockStorage, MockApi, ZapperQuerier>;
+
+fn gamm_share() -> String {
+    format!("gamm/pool/{GAMM_POOL_ID}")
+}
+
+fn proto_coin(denom: &str, amount: u128) -> ProtoCoin {
+    ProtoCoin {
+        denom: denom.to_string(),
+        amount: amount.to_string(),
+    }
+}
+
+fn position_detail(
+    spread_rewards: Vec<ProtoCoin>,
+    incentives: Vec<ProtoCoin>,
+) -> FullPositionBreakdown {
+    FullPositionBreakdown {
+        position: Some(Position {
+            position_id: POSITION_ID,
+            address: USER.to_string(),
+            pool_id: POOL_ID,
+            lower_tick: -1000,
+            upper_tick: 1000,
+            join_time: None,
+            liquidity: "1000.000000000000000000".to_string(),
+        }),
+        asset0: Some(proto_coin(TOKEN_0, 1000)),
+        asset1: Some(proto_coin(TOKEN_1, 1000)),
+        claimable_spread_rewards: spread_rewards,
+        claimable_incentives: incentives,
+        forfeited_incentives: vec![],
+    }
+}
+
+// Position transferred to the contract without going through the zapper
+fn held_position_detail() -> FullPositionBreakdown {
+    let mut detail = position_detail(vec![], vec![]);
+    if let Some(position) = detail.position.as_mut() {
+        position.address = MOCK_CONTRACT_ADDR.to_string();
+    }
+    detail
+}
+
+// Instantiated contract without any protocol fee
+fn mock_deps(position: FullPositionBreakdown) -> ZapperDeps {
+    let mut deps = OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: ZapperQuerier {
+            base: MockQuerier::default(),
+            positions: vec![position],
+            pools: vec![ConcentratedPool {
+                id: POOL_ID,
+                token0: TOKEN_0.to_string(),
+                token1: TOKEN_1.to_string(),
+                current_sqrt_price: "1.000000000000000000".to_string(),
+                tick_spacing: 100,
+                spread_factor: "0.002000000000000000".to_string(),
+                ..Default::default()
+            }],
+            gamm_pools: vec![BalancerPool {
+                id: GAMM_POOL_ID,
+                total_shares: Some(proto_coin(&gamm_share(), 1_000_000)),
+                pool_assets: vec![
+                    PoolAsset {
+                        token: Some(proto_coin(TOKEN_0, 1_000_000)),
+                        weight: "1".to_string(),
+                    },
+                    PoolAsset {
+                        token: Some(proto_coin(TOKEN_1, 1_000_000)),
+                        weight: "1".to_string(),
+                    },
+                ],
+                ..Default::default()
+            }],
+        },
+        custom_query_type: PhantomData,
+    };
+
+    instantiate(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        InstantiateMsg { owner: None },
+    )
+    .unwrap();
+    deps
+}
+
+fn register_protocol_fee(deps: &mut ZapperDeps, percent: Decimal, fee_receivers: Vec<FeeReceiver>) {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::RegisterProtocolFee {
+            percent,
+            fee_receiver: Addr::unchecked("treasury"),
+            fee_receivers,
+        },
+    )
+    .unwrap();
+}
+
+// Contract with a 1% protocol fee and fees already accrued from earlier zaps
+fn setup(accrued: &[Coin], position: FullPositionBreakdown) -> ZapperDeps {
+    let mut deps = mock_deps(position);
+    register_protocol_fee(&mut deps, Decimal::percent(1), vec![]);
+
+    for fee in accrued {
+        ACCRUED_FEES
+            .save(deps.as_mut().storage, &fee.denom, &fee.amount)
+            .unwrap();
+    }
+    set_balances(&mut deps, accrued);
+    deps
+}
+
+fn set_balances(deps: &mut ZapperDeps, balances: &[Coin]) {
+    deps.querier
+        .base
+        .update_balance(MOCK_CONTRACT_ADDR, balances.to_vec());
+}
+
+fn accrued_fee(deps: &ZapperDeps, denom: &str) -> Uint128 {
+    ACCRUED_FEES
+        .may_load(deps.as_ref().storage, denom)
+        .unwrap()
+        .unwrap_or_default()
+}
+
+fn swap_route(token_in: &str, token_out: &str, offer_amount: u128) -> Route {
+    Route {
+        token_in: token_in.to_string(),
+        offer_amount: Uint128::new(offer_amount),
+        operations: vec![SwapOperation {
+            pool: "2".to_string(),
+            denom_in: token_in.to_string(),
+            denom_out: token_out.to_string(),
+            interface: None,
+        }],
+        minimum_receive: None,
+    }
+}
+
+fn zap_in_hook_msg() -> Cw20HookMsg {
+    Cw20HookMsg::ZapInLiquidity {
+        pool_id: POOL_ID,
+        token_0: TOKEN_0.to_string(),
+        token_1: TOKEN_1.to_string(),
+        lower_tick: -1000,
+        upper_tick: 1000,
+        token_min_amount_0: None,
+        token_min_amount_1: None,
+        routes: vec![],
+        optimal_swap: None,
+        deadline: None,
+        referral: None,
+    }
+}
+
+fn zap_out_msg() -> ExecuteMsg {
+    zap_out_msg_with_ibc_return(None)
+}
+
+fn zap_out_msg_with_ibc_return(ibc_return: Option<IbcReturn>) -> ExecuteMsg {
+    ExecuteMsg::ZapOutLiquidity {
+        position_id: POSITION_ID,
+        routes: vec![],
+        liquidity_amount: None,
+        percent: None,
+        minimum_receive: None,
+        deadline: None,
+        ibc_return,
+        referral: None,
+    }
+}
+
+fn ibc_return(channel: &str, timeout: u64) -> IbcReturn {
+    IbcReturn {
+        channel: channel.to_string(),
+        receiver: "remote".to_string(),
+        timeout,
+        memo: None,
+    }
+}
+
+fn register_converter(deps: &mut ZapperDeps) {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::RegisterCw20Converter {
+            cw20_address: Addr::unchecked(CW20),
+            converter: Some(Cw20Converter {
+                contract: Addr::unchecked("converter"),
+                native_denom: TOKEN_0.to_string(),
+            }),
+        },
+    )
+    .unwrap();
+}
+
+// Registers CW20 with a converter into TOKEN_0 and sends amount of it to the contract
+fn receive_cw20(deps: &mut ZapperDeps, amount: u128) -> Result<Response, ContractError> {
+    register_converter(deps);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(CW20, &[]),
+        ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: USER.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(&zap_in_hook_msg()).unwrap(),
+        }),
+    )
+}
+
+fn zap_in_msg(routes: Vec<Route>) -> ExecuteMsg {
+    zap_in_msg_with_referral(routes, None)
+}
+
+fn zap_in_msg_with_referral(routes: Vec<Route>, referral: Option<Referral>) -> ExecuteMsg {
+    ExecuteMsg::ZapInLiquidity {
+        pool_id: POOL_ID,
+        token_0: TOKEN_0.to_string(),
+        token_1: TOKEN_1.to_string(),
+        lower_tick: -1000,
+        upper_tick: 1000,
+        token_min_amount_0: None,
+        token_min_amount_1: None,
+        routes,
+        optimal_swap: None,
+        deadline: None,
+        referral,
+    }
+}
+
+fn reply_ok(id: u64, data: Option<Binary>) -> Reply {
+    Reply {
+        id,
+        result: SubMsgResult::Ok(SubMsgResponse {
+            events: vec![],
+            data,
+        }),
+    }
+}
+
+fn bank_sends(res: &Response) -> Vec<(String, Vec<Coin>)> {
+    res.messages
+        .iter()
+        .filter_map(|sub_msg| match &sub_msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                Some((to_address.clone(), amount.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn zap_in_refund_excludes_accrued_fees() {
+    let mut deps = setup(
+        &[coin(500, TOKEN_0), coin(300, TOKEN_1)],
+        position_detail(vec![], vec![]),
+    );
+
+    // the sent coin is already in the contract balance when the zap executes
+    set_balances(&mut deps, &[coin(1500, TOKEN_0), coin(300, TOKEN_1)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        ExecuteMsg::ZapInLiquidity {
+            pool_id: POOL_ID,
+            token_0: TOKEN_0.to_string(),
+            token_1: TOKEN_1.to_string(),
+            lower_tick: -1000,
+            upper_tick: 1000,
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            routes: vec![],
+            optimal_swap: None,
+            deadline: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(510));
+
+    // the position takes 600 of the 990 left after the fee
+    set_balances(&mut deps, &[coin(900, TOKEN_0), coin(300, TOKEN_1)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(
+            encode_reply_id(CREATE_POSITION_ID, 1),
+            Some(
+                MsgCreatePositionResponse {
+                    position_id: 8,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(390, TOKEN_0)])]
+    );
+}
+
+#[test]
+fn zap_out_refund_excludes_accrued_fees() {
+    let mut deps = setup(
+        &[coin(500, TOKEN_0), coin(300, TOKEN_1)],
+        position_detail(vec![], vec![]),
+    );
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::ZapOutLiquidity {
+            position_id: POSITION_ID,
+            routes: vec![swap_route(TOKEN_0, TOKEN_1, 100)],
+            liquidity_amount: None,
+            percent: None,
+            minimum_receive: None,
+            deadline: None,
+            ibc_return: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+
+    // the whole position is withdrawn
+    set_balances(&mut deps, &[coin(1500, TOKEN_0), coin(1300, TOKEN_1)]);
+    reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 1), None),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(501));
+
+    // 99 TOKEN_0 are swapped into 50 TOKEN_1
+    set_balances(&mut deps, &[coin(1401, TOKEN_0), coin(1350, TOKEN_1)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::TransferFundsBack {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            minimum_receive: vec![],
+            ibc_return: None,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![
+            (USER.to_string(), vec![coin(900, TOKEN_0)]),
+            (USER.to_string(), vec![coin(1050, TOKEN_1)]),
+        ]
+    );
+}
+
+#[test]
+fn auto_compound_refund_excludes_accrued_fees() {
+    let mut deps = setup(
+        &[coin(500, TOKEN_0), coin(300, TOKEN_1), coin(50, REWARD)],
+        position_detail(
+            vec![proto_coin(TOKEN_0, 100)],
+            vec![proto_coin(REWARD, 200)],
+        ),
+    );
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::AutoCompound {
+            position_id: POSITION_ID,
+            routes: vec![swap_route(REWARD, TOKEN_1, 200)],
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            deadline: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, REWARD), Uint128::new(52));
+
+    // rewards are collected and 198 REWARD are swapped into 150 TOKEN_1
+    set_balances(
+        &mut deps,
+        &[coin(600, TOKEN_0), coin(450, TOKEN_1), coin(52, REWARD)],
+    );
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::AddToPosition {
+            position_id: POSITION_ID,
+            token_0: TOKEN_0.to_string(),
+            token_1: TOKEN_1.to_string(),
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            nonce: 1,
+        },
+    )
+    .unwrap();
+    let CosmosMsg::Stargate { value, .. } = &res.messages[0].msg else {
+        panic!("expected MsgAddToPosition");
+    };
+    let add_to_position = MsgAddToPosition::try_from(value.clone()).unwrap();
+    assert_eq!(
+        (add_to_position.amount0, add_to_position.amount1),
+        ("100".to_string(), "150".to_string())
+    );
+
+    // the position takes 80 TOKEN_0 and all of TOKEN_1
+    set_balances(
+        &mut deps,
+        &[coin(520, TOKEN_0), coin(300, TOKEN_1), coin(52, REWARD)],
+    );
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(
+            encode_reply_id(ADD_TO_POSITION_ID, 1),
+            Some(
+                MsgAddToPositionResponse {
+                    position_id: 8,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(20, TOKEN_0)])]
+    );
+}
+
+#[test]
+fn withdraw_checks_the_total_of_each_denom() {
+    let mut deps = setup(&[coin(500, TOKEN_0)], position_detail(vec![], vec![]));
+    set_balances(&mut deps, &[coin(800, TOKEN_0)]);
+
+    let withdraw = |amounts: &[u128]| ExecuteMsg::Withdraw {
+        assets: amounts
+            .iter()
+            .map(|amount| Asset::Native(coin(*amount, TOKEN_0)))
+            .collect(),
+        recipient: None,
+    };
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        withdraw(&[200, 200]),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::WithdrawAccruedFees(denom) if denom == TOKEN_0));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        withdraw(&[150, 150]),
+    )
+    .unwrap();
+}
+
+#[test]
+fn zap_in_converted_excludes_accrued_fees() {
+    let mut deps = setup(&[coin(500, TOKEN_0)], position_detail(vec![], vec![]));
+    receive_cw20(&mut deps, 100).unwrap();
+
+    // the converter sends 100 TOKEN_0 back for the cw20
+    set_balances(&mut deps, &[coin(600, TOKEN_0)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::ZapInConverted {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            native_denom: TOKEN_0.to_string(),
+            msg: zap_in_hook_msg(),
+        },
+    )
+    .unwrap();
+
+    // only the converted amount is zapped in and charged the fee
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(501));
+}
+
+#[test]
+fn zap_in_multi_charges_the_cw20_fee_once_converted() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    register_converter(&mut deps);
+
+    set_balances(&mut deps, &[coin(100, TOKEN_1)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(100, TOKEN_1)]),
+        ExecuteMsg::ZapInLiquidityMulti {
+            pool_id: POOL_ID,
+            token_0: TOKEN_0.to_string(),
+            token_1: TOKEN_1.to_string(),
+            lower_tick: -1000,
+            upper_tick: 1000,
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            cw20_asset: Some(Cw20Coin {
+                address: CW20.to_string(),
+                amount: Uint128::new(200),
+            }),
+            routes: vec![],
+            deadline: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+
+    // nothing is charged until the cw20 is converted, the sent funds are forwarded
+    assert_eq!(accrued_fee(&deps, TOKEN_1), Uint128::zero());
+    let CosmosMsg::Wasm(WasmMsg::Execute { funds, .. }) = &res.messages[1].msg else {
+        panic!("expected ZapInConverted");
+    };
+    assert_eq!(funds, &vec![coin(100, TOKEN_1)]);
+
+    set_balances(&mut deps, &[coin(200, TOKEN_0), coin(100, TOKEN_1)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[coin(100, TOKEN_1)]),
+        ExecuteMsg::ZapInConverted {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            native_denom: TOKEN_0.to_string(),
+            msg: Cw20HookMsg::ZapInLiquidityMulti {
+                pool_id: POOL_ID,
+                token_0: TOKEN_0.to_string(),
+                token_1: TOKEN_1.to_string(),
+                lower_tick: -1000,
+                upper_tick: 1000,
+                token_min_amount_0: None,
+                token_min_amount_1: None,
+                routes: vec![],
+                deadline: None,
+                referral: None,
+            },
+        },
+    )
+    .unwrap();
+
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(2));
+    assert_eq!(accrued_fee(&deps, TOKEN_1), Uint128::new(1));
+}
+
+#[test]
+fn zap_out_pulls_the_position_through_authz() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_msg(),
+    )
+    .unwrap();
+
+    let CosmosMsg::Stargate { type_url, .. } = &res.messages[0].msg else {
+        panic!("expected MsgExec");
+    };
+    assert_eq!(type_url, "/cosmos.authz.v1beta1.MsgExec");
+    assert_eq!(
+        POSITION_OWNERS
+            .load(deps.as_ref().storage, POSITION_ID)
+            .unwrap(),
+        USER
+    );
+}
+
+#[test]
+fn zap_out_rejects_a_non_depositor() {
+    // the position is still owned by the user
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("attacker", &[]),
+        zap_out_msg(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    // the position is escrowed for the user
+    let mut deps = setup(&[], held_position_detail());
+    POSITION_OWNERS
+        .save(deps.as_mut().storage, POSITION_ID, &Addr::unchecked(USER))
+        .unwrap();
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("attacker", &[]),
+        zap_out_msg(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+}
+
+#[test]
+fn register_position_owner_recovers_a_held_position() {
+    let mut deps = setup(&[], held_position_detail());
+    let register = ExecuteMsg::RegisterPositionOwner {
+        position_id: POSITION_ID,
+        owner: Addr::unchecked(USER),
+    };
+
+    // without a depositor nobody can zap out the position
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_msg(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        register.clone(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Admin(_)));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        register.clone(),
+    )
+    .unwrap();
+    let err = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), register).unwrap_err();
+    assert!(matches!(err, ContractError::PositionNotHeld(POSITION_ID)));
+
+    // the escrowed position is withdrawn without an authz pull
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_msg(),
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 1);
+}
+
+#[test]
+fn migrate_position_bounds_the_rebalance_swap() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let migrate = |max_slippage: Decimal| ExecuteMsg::MigratePosition {
+        position_id: POSITION_ID,
+        new_lower_tick: -1000,
+        new_upper_tick: 1000,
+        paths: vec![swap_route(TOKEN_0, TOKEN_1, 0).operations],
+        max_slippage,
+        token_min_amount_0: None,
+        token_min_amount_1: None,
+        deadline: None,
+    };
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        migrate(Decimal::percent(101)),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::InvalidMaxSlippage));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        migrate(Decimal::percent(1)),
+    )
+    .unwrap();
+
+    // the whole position comes back in TOKEN_0
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(MIGRATE_POSITION_ID, 1), None),
+    )
+    .unwrap();
+
+    // at a pool price of 1 the swap must return the offer less 1%
+    let CosmosMsg::Stargate { value, .. } = &res.messages[0].msg else {
+        panic!("expected MsgSwapExactAmountIn");
+    };
+    let swap = MsgSwapExactAmountIn::try_from(value.clone()).unwrap();
+    let offer_amount: u128 = swap.token_in.unwrap().amount.parse().unwrap();
+    assert!(offer_amount > 0);
+    assert_eq!(
+        swap.token_out_min_amount,
+        (offer_amount * 99 / 100).to_string()
+    );
+}
+
+#[test]
+fn zap_in_refunds_what_the_routes_leave_of_the_sent_coin() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    set_balances(&mut deps, &[coin(1000, REWARD)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, REWARD)]),
+        ExecuteMsg::ZapInLiquidity {
+            pool_id: POOL_ID,
+            token_0: TOKEN_0.to_string(),
+            token_1: TOKEN_1.to_string(),
+            lower_tick: -1000,
+            upper_tick: 1000,
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            routes: vec![swap_route(REWARD, TOKEN_0, 500)],
+            optimal_swap: None,
+            deadline: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+
+    // 490 REWARD are left after the fee and the swap, the position takes all of TOKEN_0
+    set_balances(&mut deps, &[coin(500, REWARD)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(
+            encode_reply_id(CREATE_POSITION_ID, 1),
+            Some(
+                MsgCreatePositionResponse {
+                    position_id: 8,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(490, REWARD)])]
+    );
+}
+
+#[test]
+fn zap_into_position_refunds_what_the_routes_leave_of_the_sent_coin() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    set_balances(&mut deps, &[coin(1000, REWARD)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, REWARD)]),
+        ExecuteMsg::ZapIntoPosition {
+            position_id: POSITION_ID,
+            routes: vec![swap_route(REWARD, TOKEN_0, 500)],
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            deadline: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+
+    set_balances(&mut deps, &[coin(500, REWARD)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(
+            encode_reply_id(ADD_TO_POSITION_ID, 1),
+            Some(
+                MsgAddToPositionResponse {
+                    position_id: 8,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(490, REWARD)])]
+    );
+}
+
+#[test]
+fn claim_fees_pays_a_repeated_denom_once() {
+    let mut deps = setup(&[coin(500, TOKEN_0)], position_detail(vec![], vec![]));
+    set_balances(&mut deps, &[coin(800, TOKEN_0)]);
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::ClaimFees {
+            denoms: Some(vec![TOKEN_0.to_string(), TOKEN_0.to_string()]),
+        },
+    )
+    .unwrap();
+
+    assert_eq!(
+        bank_sends(&res),
+        vec![("treasury".to_string(), vec![coin(500, TOKEN_0)])]
+    );
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::zero());
+}
+
+#[test]
+fn zap_out_rejects_an_unusable_ibc_return() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    for ibc_return in [ibc_return("channel-0", 0), ibc_return("", 600)] {
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(USER, &[]),
+            zap_out_msg_with_ibc_return(Some(ibc_return)),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidIbcReturn));
+    }
+}
+
+#[test]
+fn ibc_return_refunds_failed_transfers() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_msg_with_ibc_return(Some(ibc_return("channel-0", 600))),
+    )
+    .unwrap();
+    set_balances(&mut deps, &[coin(1000, TOKEN_0), coin(1000, TOKEN_1)]);
+    reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 1), None),
+    )
+    .unwrap();
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::TransferFundsBack {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            minimum_receive: vec![],
+            ibc_return: Some(ibc_return("channel-0", 600)),
+        },
+    )
+    .unwrap();
+    assert_eq!(res.messages.len(), 2);
+
+    // each transfer reply records the sequence of the next coin in transfer order
+    for sequence in [5, 6] {
+        reply(
+            deps.as_mut(),
+            mock_env(),
+            reply_ok(
+                encode_reply_id(IBC_TRANSFER_ID, 1),
+                Some(MsgTransferResponse { sequence }.into()),
+            ),
+        )
+        .unwrap();
+    }
+    assert!(!PENDING_IBC_TRANSFERS.has(deps.as_ref().storage, 1));
+    for (sequence, denom) in [(5, TOKEN_0), (6, TOKEN_1)] {
+        assert_eq!(
+            IBC_TRANSFERS
+                .load(deps.as_ref().storage, ("channel-0", sequence))
+                .unwrap(),
+            IbcTransfer {
+                receiver: Addr::unchecked(USER),
+                coin: coin(1000, denom),
+            }
+        );
+    }
+
+    // a failed ack and a timeout refund the local receiver
+    let res = sudo(
+        deps.as_mut(),
+        mock_env(),
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcAck {
+            channel: "channel-0".to_string(),
+            sequence: 5,
+            ack: String::new(),
+            success: false,
+        }),
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(1000, TOKEN_0)])]
+    );
+    let res = sudo(
+        deps.as_mut(),
+        mock_env(),
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+            channel: "channel-0".to_string(),
+            sequence: 6,
+        }),
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(1000, TOKEN_1)])]
+    );
+
+    // each transfer is refunded at most once
+    let res = sudo(
+        deps.as_mut(),
+        mock_env(),
+        SudoMsg::IbcLifecycleComplete(IbcLifecycleComplete::IbcTimeout {
+            channel: "channel-0".to_string(),
+            sequence: 6,
+        }),
+    )
+    .unwrap();
+    assert!(res.messages.is_empty());
+}
+
+#[test]
+fn receive_cw20_rejects_an_unregistered_cw20() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(CW20, &[]),
+        ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: USER.to_string(),
+            amount: Uint128::new(100),
+            msg: to_json_binary(&zap_in_hook_msg()).unwrap(),
+        }),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::AssetNotNative));
+}
+
+#[test]
+fn zap_in_converted_zaps_the_converted_amount() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    set_balances(&mut deps, &[coin(300, TOKEN_0)]);
+    let res = receive_cw20(&mut deps, 100).unwrap();
+
+    let CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr, msg, ..
+    }) = &res.messages[0].msg
+    else {
+        panic!("expected the cw20 send to the converter");
+    };
+    assert_eq!(contract_addr, CW20);
+    assert!(matches!(
+        from_json(msg).unwrap(),
+        Cw20ExecuteMsg::Send { contract, amount, .. }
+            if contract == "converter" && amount == Uint128::new(100)
+    ));
+
+    // only the contract can continue the zap
+    set_balances(&mut deps, &[coin(400, TOKEN_0)]);
+    let zap_in_converted = ExecuteMsg::ZapInConverted {
+        receiver: Addr::unchecked(USER),
+        nonce: 1,
+        native_denom: TOKEN_0.to_string(),
+        msg: zap_in_hook_msg(),
+    };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_in_converted.clone(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        zap_in_converted,
+    )
+    .unwrap();
+
+    // the balance held before the conversion stays out of the zap
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(1));
+    assert_eq!(
+        SNAP_BALANCES
+            .load(deps.as_ref().storage, (2, TOKEN_0))
+            .unwrap(),
+        Uint128::new(300)
+    );
+}
+
+#[test]
+fn ownership_is_transferred_once_accepted() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let propose = |new_owner: &str, expiry: Option<Expiration>| ExecuteMsg::ProposeNewOwner {
+        new_owner: Addr::unchecked(new_owner),
+        expiry,
+    };
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        propose(USER, None),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Admin(_)));
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        propose("New_Owner", None),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Std(_)));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        propose("new_owner", None),
+    )
+    .unwrap();
+
+    // the owner stays in charge until the proposal is accepted by the new owner
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap();
+
+    let owner: Option<Addr> =
+        from_json(query(deps.as_ref(), mock_env(), QueryMsg::Owner {}).unwrap()).unwrap();
+    assert_eq!(owner, Some(Addr::unchecked("new_owner")));
+    assert!(PENDING_OWNER
+        .may_load(deps.as_ref().storage)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn ownership_proposal_can_be_cancelled() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::ProposeNewOwner {
+            new_owner: Addr::unchecked("new_owner"),
+            expiry: None,
+        },
+    )
+    .unwrap();
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::CancelOwnershipProposal {},
+    )
+    .unwrap();
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::NoPendingOwner));
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::CancelOwnershipProposal {},
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::NoPendingOwner));
+}
+
+#[test]
+fn expired_ownership_proposals_are_rejected() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let env = mock_env();
+    let propose = |expiry: Expiration| ExecuteMsg::ProposeNewOwner {
+        new_owner: Addr::unchecked("new_owner"),
+        expiry: Some(expiry),
+    };
+
+    let err = execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info(OWNER, &[]),
+        propose(Expiration::AtHeight(env.block.height)),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::OwnershipProposalExpired));
+
+    execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info(OWNER, &[]),
+        propose(Expiration::AtHeight(env.block.height + 1)),
+    )
+    .unwrap();
+    let mut later = env;
+    later.block.height += 1;
+    let err = execute(
+        deps.as_mut(),
+        later,
+        mock_info("new_owner", &[]),
+        ExecuteMsg::AcceptOwnership {},
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::OwnershipProposalExpired));
+}
+
+#[test]
+fn claim_fees_accrued_through_a_pool_fee_override() {
+    let mut deps = mock_deps(position_detail(vec![], vec![]));
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::SetPoolFee {
+            pool_id: POOL_ID,
+            direction: FeeDirection::ZapIn,
+            percent: Decimal::percent(2),
+        },
+    )
+    .unwrap();
+
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg(vec![]),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(20));
+
+    let claim = ExecuteMsg::ClaimFees { denoms: None };
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        claim.clone(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::FeeReceiversNotConfigured));
+
+    // receivers are configured without a global fee
+    register_protocol_fee(&mut deps, Decimal::zero(), vec![]);
+    let res = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), claim).unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![("treasury".to_string(), vec![coin(20, TOKEN_0)])]
+    );
+}
+
+fn fee_receiver(address: &str, weight: u64) -> FeeReceiver {
+    FeeReceiver {
+        address: Addr::unchecked(address),
+        weight,
+    }
+}
+
+fn fee_split(fee_receivers: Vec<FeeReceiver>, amount: u128) -> Vec<(String, u128)> {
+    ProtocolFee {
+        percent: Decimal::percent(1),
+        fee_receiver: Addr::unchecked("treasury"),
+        fee_receivers,
+    }
+    .split(Uint128::new(amount))
+    .into_iter()
+    .map(|(address, share)| (address.to_string(), share.u128()))
+    .collect()
+}
+
+#[test]
+fn protocol_fee_split_follows_uneven_weights() {
+    assert_eq!(
+        fee_split(vec![fee_receiver("alice", 1), fee_receiver("bob", 3)], 1000),
+        vec![("alice".to_string(), 250), ("bob".to_string(), 750)]
+    );
+}
+
+#[test]
+fn protocol_fee_split_sends_the_rounding_dust_to_the_fee_receiver() {
+    assert_eq!(
+        fee_split(
+            vec![
+                fee_receiver("alice", 1),
+                fee_receiver("bob", 1),
+                fee_receiver("carol", 1)
+            ],
+            100
+        ),
+        vec![
+            ("alice".to_string(), 33),
+            ("bob".to_string(), 33),
+            ("carol".to_string(), 33),
+            ("treasury".to_string(), 1),
+        ]
+    );
+    // a share that rounds down to zero is not paid out
+    assert_eq!(
+        fee_split(vec![fee_receiver("alice", 1), fee_receiver("bob", 999)], 10),
+        vec![("bob".to_string(), 9), ("treasury".to_string(), 1)]
+    );
+}
+
+#[test]
+fn protocol_fee_split_pays_a_single_receiver_in_full() {
+    assert_eq!(
+        fee_split(vec![fee_receiver("alice", 7)], 1001),
+        vec![("alice".to_string(), 1001)]
+    );
+    assert_eq!(
+        fee_split(vec![], 1001),
+        vec![("treasury".to_string(), 1001)]
+    );
+}
+
+#[test]
+fn register_protocol_fee_rejects_invalid_weights() {
+    let mut deps = mock_deps(position_detail(vec![], vec![]));
+    for fee_receivers in [
+        vec![fee_receiver("alice", u64::MAX), fee_receiver("bob", 1)],
+        vec![fee_receiver("alice", 1), fee_receiver("bob", 0)],
+    ] {
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::RegisterProtocolFee {
+                percent: Decimal::percent(1),
+                fee_receiver: Addr::unchecked("treasury"),
+                fee_receivers,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidFeeReceivers));
+    }
+}
+
+// Accrued fees of both position tokens with REWARD as the treasury denom
+fn setup_fee_conversion() -> ZapperDeps {
+    let mut deps = setup(
+        &[coin(100, TOKEN_0), coin(50, TOKEN_1), coin(10, REWARD)],
+        position_detail(vec![], vec![]),
+    );
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::SetTreasuryDenom {
+            denom: REWARD.to_string(),
+        },
+    )
+    .unwrap();
+    deps
+}
+
+fn self_execute_msg(res: &Response) -> ExecuteMsg {
+    res.messages
+        .iter()
+        .find_map(|sub_msg| match &sub_msg.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => from_json(msg).ok(),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+fn convert_fees_accrues_the_swapped_amount() {
+    let mut deps = setup_fee_conversion();
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::ConvertFees {
+            routes: vec![
+                swap_route(TOKEN_0, REWARD, 100),
+                swap_route(TOKEN_1, REWARD, 20),
+            ],
+            minimum_receive: Uint128::new(80),
+        },
+    )
+    .unwrap();
+    // a fully converted denom leaves the accrued fees
+    assert_eq!(
+        ACCRUED_FEES
+            .may_load(deps.as_ref().storage, TOKEN_0)
+            .unwrap(),
+        None
+    );
+    assert_eq!(accrued_fee(&deps, TOKEN_1), Uint128::new(30));
+    assert_eq!(accrued_fee(&deps, REWARD), Uint128::new(10));
+
+    let accrue_msg = self_execute_msg(&res);
+    let ExecuteMsg::AccrueConvertedFees {
+        nonce,
+        minimum_receive,
+    } = accrue_msg.clone()
+    else {
+        panic!("expected AccrueConvertedFees");
+    };
+    assert_eq!(minimum_receive, Uint128::new(80));
+
+    // the swaps return 90 on top of the accrued 10
+    set_balances(&mut deps, &[coin(30, TOKEN_1), coin(100, REWARD)]);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        accrue_msg.clone(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::AccrueConvertedFees {
+            nonce,
+            minimum_receive: Uint128::new(95),
+        },
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ContractError::MinimumReceiveNotMet { expected, received, .. }
+            if expected == Uint128::new(95) && received == Uint128::new(90)
+    ));
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        accrue_msg,
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, REWARD), Uint128::new(100));
+    assert!(SNAP_BALANCES
+        .prefix(nonce)
+        .range(deps.as_ref().storage, None, None, Order::Ascending)
+        .next()
+        .is_none());
+}
+
+#[test]
+fn convert_fees_rejects_more_than_the_accrued_fees() {
+    let mut deps = setup_fee_conversion();
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::ConvertFees {
+            routes: vec![swap_route(TOKEN_1, REWARD, 51)],
+            minimum_receive: Uint128::zero(),
+        },
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::InsufficientAccruedFees(denom) if denom == TOKEN_1));
+    assert_eq!(accrued_fee(&deps, TOKEN_1), Uint128::new(50));
+}
+
+fn set_max_referral_bps(deps: &mut ZapperDeps, max_bps: u16) {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::SetMaxReferralBps { max_bps },
+    )
+    .unwrap();
+}
+
+fn referral(bps: u16) -> Option<Referral> {
+    Some(Referral {
+        address: Addr::unchecked("referrer"),
+        bps,
+    })
+}
+
+fn referral_fees(deps: &ZapperDeps) -> Vec<Coin> {
+    from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::ReferralFees {
+                referrer: Addr::unchecked("referrer"),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn referral_fees_are_capped_by_the_max_referral_bps() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    // without a cap no referral fee is allowed
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg_with_referral(vec![], referral(1)),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ContractError::ReferralFeeTooHigh { bps: 1, max_bps: 0 }
+    ));
+
+    set_max_referral_bps(&mut deps, 100);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::SetMaxReferralBps { max_bps: 10_001 },
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Zapper(_)));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg_with_referral(vec![], referral(101)),
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ContractError::ReferralFeeTooHigh {
+            bps: 101,
+            max_bps: 100
+        }
+    ));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::ZapOutLiquidity {
+            position_id: POSITION_ID,
+            routes: vec![],
+            liquidity_amount: None,
+            percent: None,
+            minimum_receive: None,
+            deadline: None,
+            ibc_return: None,
+            referral: referral(101),
+        },
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::ReferralFeeTooHigh { .. }));
+    assert!(referral_fees(&deps).is_empty());
+}
+
+#[test]
+fn referral_fees_are_paid_and_totalled_per_referrer() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    set_max_referral_bps(&mut deps, 100);
+
+    // 1% referral fee on the 990 left after the protocol fee
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg_with_referral(vec![], referral(100)),
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![("referrer".to_string(), vec![coin(9, TOKEN_0)])]
+    );
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(10));
+    assert_eq!(referral_fees(&deps), vec![coin(9, TOKEN_0)]);
+
+    // zap out pays the referral fee on the swapped amount after the protocol fee
+    set_balances(&mut deps, &[coin(10, TOKEN_0)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::ZapOutLiquidity {
+            position_id: POSITION_ID,
+            routes: vec![swap_route(TOKEN_0, TOKEN_1, 1000)],
+            liquidity_amount: None,
+            percent: None,
+            minimum_receive: None,
+            deadline: None,
+            ibc_return: None,
+            referral: referral(50),
+        },
+    )
+    .unwrap();
+    set_balances(&mut deps, &[coin(1010, TOKEN_0), coin(1000, TOKEN_1)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 2), None),
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![("referrer".to_string(), vec![coin(4, TOKEN_0)])]
+    );
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(20));
+    assert_eq!(referral_fees(&deps), vec![coin(13, TOKEN_0)]);
+}
+
+fn effective_fee(deps: &ZapperDeps, pool_id: u64) -> EffectiveFeeResponse {
+    from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::EffectiveFee { pool_id },
+        )
+        .unwrap(),
+    )
+    .unwrap()
+}
+
+fn set_pool_fee(deps: &mut ZapperDeps, pool_id: u64, direction: FeeDirection, percent: u64) {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::SetPoolFee {
+            pool_id,
+            direction,
+            percent: Decimal::percent(percent),
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn pool_fee_overrides_take_precedence_over_the_global_fee() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    set_pool_fee(&mut deps, POOL_ID, FeeDirection::ZapIn, 3);
+    set_pool_fee(&mut deps, POOL_ID, FeeDirection::ZapOut, 0);
+    set_pool_fee(&mut deps, 2, FeeDirection::ZapOut, 5);
+
+    // each direction of a pool is overridden on its own, a zero override included
+    let storage = deps.as_ref().storage;
+    assert_eq!(
+        protocol_fee_percent(storage, POOL_ID, FeeDirection::ZapIn).unwrap(),
+        Decimal::percent(3)
+    );
+    assert_eq!(
+        protocol_fee_percent(storage, POOL_ID, FeeDirection::ZapOut).unwrap(),
+        Decimal::zero()
+    );
+    assert_eq!(
+        effective_fee(&deps, 2),
+        EffectiveFeeResponse {
+            zap_in: Decimal::percent(1),
+            zap_out: Decimal::percent(5),
+        }
+    );
+    assert_eq!(
+        effective_fee(&deps, 3),
+        EffectiveFeeResponse {
+            zap_in: Decimal::percent(1),
+            zap_out: Decimal::percent(1),
+        }
+    );
+
+    // the zap is charged the override
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg(vec![]),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(30));
+
+    // a removed override falls back to the global fee
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(OWNER, &[]),
+        ExecuteMsg::RemovePoolFee {
+            pool_id: POOL_ID,
+            direction: FeeDirection::ZapIn,
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        effective_fee(&deps, POOL_ID),
+        EffectiveFeeResponse {
+            zap_in: Decimal::percent(1),
+            zap_out: Decimal::zero(),
+        }
+    );
+}
+
+#[test]
+fn pool_fee_overrides_apply_without_a_global_fee() {
+    let mut deps = mock_deps(position_detail(vec![], vec![]));
+    assert_eq!(
+        effective_fee(&deps, POOL_ID),
+        EffectiveFeeResponse {
+            zap_in: Decimal::zero(),
+            zap_out: Decimal::zero(),
+        }
+    );
+
+    set_pool_fee(&mut deps, POOL_ID, FeeDirection::ZapOut, 2);
+    assert_eq!(
+        effective_fee(&deps, POOL_ID),
+        EffectiveFeeResponse {
+            zap_in: Decimal::zero(),
+            zap_out: Decimal::percent(2),
+        }
+    );
+}
+
+#[test]
+fn register_cw20_converter_validates_the_addresses() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let register = |cw20_address: &str, contract: &str| ExecuteMsg::RegisterCw20Converter {
+        cw20_address: Addr::unchecked(cw20_address),
+        converter: Some(Cw20Converter {
+            contract: Addr::unchecked(contract),
+            native_denom: TOKEN_0.to_string(),
+        }),
+    };
+
+    for msg in [
+        register("CW20TOKEN", "converter"),
+        register(CW20, "Converter"),
+    ] {
+        let err = execute(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Std(_)));
+    }
+}
+
+fn ibc_hook_zap_in(deps: &mut ZapperDeps) -> Response {
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("ibchooks", &[coin(1000, TOKEN_0)]),
+        ExecuteMsg::IbcHookZapIn {
+            receiver: USER.to_string(),
+            recovery_address: "recovery".to_string(),
+            msg: zap_in_hook_msg(),
+        },
+    )
+    .unwrap();
+
+    // the zap in runs as a submessage carrying the hook funds
+    assert_eq!(res.messages.len(), 1);
+    assert_eq!(res.messages[0].id, encode_reply_id(IBC_ZAP_IN_ID, 1));
+    let CosmosMsg::Wasm(WasmMsg::Execute { msg, funds, .. }) = &res.messages[0].msg else {
+        panic!("expected a wasm execute");
+    };
+    assert_eq!(funds, &vec![coin(1000, TOKEN_0)]);
+    assert!(matches!(
+        from_json(msg).unwrap(),
+        ExecuteMsg::ZapInFor { receiver, .. } if receiver == USER
+    ));
+    res
+}
+
+#[test]
+fn ibc_hook_zap_in_success_keeps_the_funds() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    ibc_hook_zap_in(&mut deps);
+
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(IBC_ZAP_IN_ID, 1), None),
+    )
+    .unwrap();
+    assert!(res.messages.is_empty());
+    assert!(!PENDING_IBC_ZAP_INS.has(deps.as_ref().storage, 1));
+}
+
+#[test]
+fn ibc_hook_zap_in_failure_refunds_the_recovery_address() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    ibc_hook_zap_in(&mut deps);
+
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        Reply {
+            id: encode_reply_id(IBC_ZAP_IN_ID, 1),
+            result: SubMsgResult::Err("zap in failed".to_string()),
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![("recovery".to_string(), vec![coin(1000, TOKEN_0)])]
+    );
+    assert!(!PENDING_IBC_ZAP_INS.has(deps.as_ref().storage, 1));
+
+    // only the contract can zap in for a receiver
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        ExecuteMsg::ZapInFor {
+            receiver: Addr::unchecked(USER),
+            msg: zap_in_hook_msg(),
+        },
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+}
+
+fn zap_out_partial_msg(liquidity_amount: Option<&str>, percent: Option<u64>) -> ExecuteMsg {
+    ExecuteMsg::ZapOutLiquidity {
+        position_id: POSITION_ID,
+        routes: vec![],
+        liquidity_amount: liquidity_amount.map(|amount| amount.parse().unwrap()),
+        percent: percent.map(Decimal::percent),
+        minimum_receive: None,
+        deadline: None,
+        ibc_return: None,
+        referral: None,
+    }
+}
+
+fn stargate_values(res: &Response, msg_type_url: &str) -> Vec<Binary> {
+    res.messages
+        .iter()
+        .filter_map(|sub_msg| match &sub_msg.msg {
+            CosmosMsg::Stargate { type_url, value } if type_url == msg_type_url => {
+                Some(value.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Zaps out part of the position and returns the withdrawn liquidity and the transfers
+// of the residual position
+fn zap_out_partially(
+    liquidity_amount: Option<&str>,
+    percent: Option<u64>,
+) -> (String, Vec<MsgTransferPositions>) {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_partial_msg(liquidity_amount, percent),
+    )
+    .unwrap();
+    let withdraw = stargate_values(&res, MsgWithdrawPosition::TYPE_URL);
+    let withdraw = MsgWithdrawPosition::try_from(withdraw[0].clone()).unwrap();
+    assert_eq!(withdraw.position_id, POSITION_ID);
+
+    set_balances(&mut deps, &[coin(250, TOKEN_0), coin(250, TOKEN_1)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 1), None),
+    )
+    .unwrap();
+    let transfers = stargate_values(&res, MsgTransferPositions::TYPE_URL)
+        .into_iter()
+        .map(|value| MsgTransferPositions::try_from(value).unwrap())
+        .collect();
+    (withdraw.liquidity_amount, transfers)
+}
+
+#[test]
+fn partial_zap_out_hands_the_residual_position_back() {
+    let residual_transfer = vec![MsgTransferPositions {
+        position_ids: vec![POSITION_ID],
+        sender: MOCK_CONTRACT_ADDR.to_string(),
+        new_owner: USER.to_string(),
+    }];
+    // liquidity is scaled by 1e18
+    let withdrawn = "250000000000000000000".to_string();
+
+    assert_eq!(
+        zap_out_partially(Some("250"), None),
+        (withdrawn.clone(), residual_transfer.clone())
+    );
+    assert_eq!(
+        zap_out_partially(None, Some(25)),
+        (withdrawn, residual_transfer)
+    );
+}
+
+#[test]
+fn zap_out_without_an_amount_withdraws_the_whole_position() {
+    assert_eq!(
+        zap_out_partially(None, None),
+        ("1000000000000000000000".to_string(), vec![])
+    );
+    assert_eq!(
+        zap_out_partially(None, Some(100)),
+        ("1000000000000000000000".to_string(), vec![])
+    );
+}
+
+#[test]
+fn zap_out_rejects_an_invalid_withdraw_amount() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_partial_msg(Some("250"), Some(25)),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::ZapOutLiquidityAndPercentSet));
+
+    for msg in [
+        zap_out_partial_msg(Some("0"), None),
+        zap_out_partial_msg(Some("1000.1"), None),
+        zap_out_partial_msg(None, Some(0)),
+    ] {
+        let err = execute(deps.as_mut(), mock_env(), mock_info(USER, &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidZapOutLiquidity));
+    }
+}
+
+fn zap_in_gamm_pool(deps: &mut ZapperDeps, routes: Vec<Route>) {
+    set_balances(deps, &[coin(1000, TOKEN_0)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        ExecuteMsg::ZapInGammPool {
+            pool_id: GAMM_POOL_ID,
+            routes,
+            share_out_min_amount: None,
+            deadline: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        self_execute_msg(&res),
+        ExecuteMsg::JoinGammPool {
+            pool_id: GAMM_POOL_ID,
+            share_out_min_amount: None,
+            nonce: 1,
+        }
+    );
+    assert_eq!(accrued_fee(deps, TOKEN_0), Uint128::new(10));
+}
+
+fn join_gamm_pool(
+    deps: &mut ZapperDeps,
+    share_out_min_amount: Option<u128>,
+) -> Result<Response, ContractError> {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::JoinGammPool {
+            pool_id: GAMM_POOL_ID,
+            share_out_min_amount: share_out_min_amount.map(Uint128::new),
+            nonce: 1,
+        },
+    )
+}
+
+// Bank sends and cw20 transfers, MockApi takes the lower case share denom for a cw20
+fn transfers(res: &Response) -> Vec<(String, Coin)> {
+    res.messages
+        .iter()
+        .filter_map(|sub_msg| match &sub_msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                Some((to_address.clone(), amount[0].clone()))
+            }
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr, msg, ..
+            }) => match from_json(msg).ok()? {
+                Cw20ExecuteMsg::Transfer { recipient, amount } => {
+                    Some((recipient, coin(amount.u128(), contract_addr)))
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn zap_in_gamm_pool_joins_single_sided() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    zap_in_gamm_pool(&mut deps, vec![]);
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::JoinGammPool {
+            pool_id: GAMM_POOL_ID,
+            share_out_min_amount: None,
+            nonce: 1,
+        },
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    // everything left after the fee goes in as TOKEN_0
+    let res = join_gamm_pool(&mut deps, Some(400)).unwrap();
+    let join = stargate_values(&res, MsgJoinSwapExternAmountIn::TYPE_URL);
+    assert_eq!(
+        MsgJoinSwapExternAmountIn::try_from(join[0].clone()).unwrap(),
+        MsgJoinSwapExternAmountIn {
+            sender: MOCK_CONTRACT_ADDR.to_string(),
+            pool_id: GAMM_POOL_ID,
+            token_in: Some(proto_coin(TOKEN_0, 990)),
+            share_out_min_amount: "400".to_string(),
+        }
+    );
+
+    set_balances(&mut deps, &[coin(10, TOKEN_0), coin(480, gamm_share())]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(JOIN_POOL_ID, 1), None),
+    )
+    .unwrap();
+    assert_eq!(
+        transfers(&res),
+        vec![(USER.to_string(), coin(480, gamm_share()))]
+    );
+}
+
+#[test]
+fn zap_in_gamm_pool_refunds_what_a_balanced_join_leaves() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    zap_in_gamm_pool(&mut deps, vec![swap_route(TOKEN_0, TOKEN_1, 500)]);
+
+    // 500 TOKEN_0 are swapped into 495 TOKEN_1
+    set_balances(&mut deps, &[coin(500, TOKEN_0), coin(495, TOKEN_1)]);
+    let err = join_gamm_pool(&mut deps, Some(491)).unwrap_err();
+    assert!(matches!(err, ContractError::JoinPoolError(_)));
+
+    // the 490 TOKEN_0 left bound the join, 5 TOKEN_1 are not used
+    let res = join_gamm_pool(&mut deps, Some(490)).unwrap();
+    let join = stargate_values(&res, MsgJoinPool::TYPE_URL);
+    assert_eq!(
+        MsgJoinPool::try_from(join[0].clone()).unwrap(),
+        MsgJoinPool {
+            sender: MOCK_CONTRACT_ADDR.to_string(),
+            pool_id: GAMM_POOL_ID,
+            share_out_amount: "490".to_string(),
+            token_in_maxs: vec![proto_coin(TOKEN_0, 490), proto_coin(TOKEN_1, 495)],
+        }
+    );
+
+    set_balances(
+        &mut deps,
+        &[coin(10, TOKEN_0), coin(5, TOKEN_1), coin(490, gamm_share())],
+    );
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(JOIN_POOL_ID, 1), None),
+    )
+    .unwrap();
+    let mut refunds = transfers(&res);
+    refunds.sort_by(|a, b| a.1.denom.cmp(&b.1.denom));
+    assert_eq!(
+        refunds,
+        vec![
+            (USER.to_string(), coin(490, gamm_share())),
+            (USER.to_string(), coin(5, TOKEN_1)),
+        ]
+    );
+}
+
+#[test]
+fn zap_out_gamm_pool_checks_the_minimum_receive() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, gamm_share())]),
+        ExecuteMsg::ZapOutGammPool {
+            routes: vec![swap_route(TOKEN_0, TOKEN_1, 500)],
+            minimum_receive: Some(vec![coin(1000, TOKEN_1)]),
+            deadline: None,
+            ibc_return: None,
+            referral: None,
+        },
+    )
+    .unwrap();
+    let exit = stargate_values(&res, MsgExitPool::TYPE_URL);
+    assert_eq!(
+        MsgExitPool::try_from(exit[0].clone()).unwrap(),
+        MsgExitPool {
+            sender: MOCK_CONTRACT_ADDR.to_string(),
+            pool_id: GAMM_POOL_ID,
+            share_in_amount: "1000".to_string(),
+            token_out_mins: vec![],
+        }
+    );
+
+    // the exit returns 500 of each token, the swap pays the 1% zap out fee
+    set_balances(&mut deps, &[coin(500, TOKEN_0), coin(500, TOKEN_1)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(EXIT_POOL_ID, 1), None),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(5));
+    let transfer_funds_back = self_execute_msg(&res);
+    assert_eq!(
+        transfer_funds_back,
+        ExecuteMsg::TransferFundsBack {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            minimum_receive: vec![coin(1000, TOKEN_1)],
+            ibc_return: None,
+        }
+    );
+
+    // 495 TOKEN_0 are swapped into 490 TOKEN_1
+    set_balances(&mut deps, &[coin(5, TOKEN_0), coin(990, TOKEN_1)]);
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        transfer_funds_back,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ContractError::MinimumReceiveNotMet { denom, expected, received }
+            if denom == TOKEN_1 && expected == Uint128::new(1000) && received == Uint128::new(990)
+    ));
+
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        ExecuteMsg::TransferFundsBack {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            minimum_receive: vec![coin(990, TOKEN_1)],
+            ibc_return: None,
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(990, TOKEN_1)])]
+    );
+}
+
+fn zap_out_to_msg(minimum_receive: Option<u128>) -> ExecuteMsg {
+    ExecuteMsg::ZapOutTo {
+        position_id: POSITION_ID,
+        target_denom: REWARD.to_string(),
+        paths: vec![
+            swap_route(TOKEN_0, REWARD, 0).operations,
+            swap_route(TOKEN_1, REWARD, 0).operations,
+        ],
+        minimum_receive: minimum_receive.map(Uint128::new),
+        deadline: None,
+        ibc_return: None,
+        referral: None,
+    }
+}
+
+// Zaps the whole position out to REWARD and returns the swaps and the transfer back
+fn zap_out_to(deps: &mut ZapperDeps, minimum_receive: Option<u128>) -> (Vec<Coin>, ExecuteMsg) {
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        zap_out_to_msg(minimum_receive),
+    )
+    .unwrap();
+
+    set_balances(deps, &[coin(1000, TOKEN_0), coin(1000, TOKEN_1)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 1), None),
+    )
+    .unwrap();
+    let swaps = stargate_values(&res, MsgSwapExactAmountIn::TYPE_URL)
+        .into_iter()
+        .map(|value| {
+            let swap = MsgSwapExactAmountIn::try_from(value).unwrap();
+            assert_eq!(swap.routes[0].token_out_denom, REWARD);
+            let token_in = swap.token_in.unwrap();
+            coin(token_in.amount.parse().unwrap(), token_in.denom)
+        })
+        .collect();
+    (swaps, self_execute_msg(&res))
+}
+
+#[test]
+fn zap_out_to_swaps_both_tokens_into_the_target() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let (swaps, transfer_funds_back) = zap_out_to(&mut deps, Some(1900));
+
+    // each token pays the 1% zap out fee before its swap
+    assert_eq!(swaps, vec![coin(990, TOKEN_0), coin(990, TOKEN_1)]);
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(10));
+    assert_eq!(accrued_fee(&deps, TOKEN_1), Uint128::new(10));
+
+    set_balances(
+        &mut deps,
+        &[coin(10, TOKEN_0), coin(10, TOKEN_1), coin(1900, REWARD)],
+    );
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        transfer_funds_back,
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), vec![coin(1900, REWARD)])]
+    );
+}
+
+#[test]
+fn transfer_funds_back_checks_the_minimum_across_all_swaps() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let (_, transfer_funds_back) = zap_out_to(&mut deps, Some(1900));
+    assert!(matches!(
+        &transfer_funds_back,
+        ExecuteMsg::TransferFundsBack { minimum_receive, .. }
+            if minimum_receive == &vec![coin(1900, REWARD)]
+    ));
+
+    // each swap returns 949, the two together fall short of the minimum
+    set_balances(
+        &mut deps,
+        &[coin(10, TOKEN_0), coin(10, TOKEN_1), coin(1898, REWARD)],
+    );
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        transfer_funds_back,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ContractError::MinimumReceiveNotMet { denom, expected, received }
+            if denom == REWARD && expected == Uint128::new(1900) && received == Uint128::new(1898)
+    ));
+    // nothing is released until the minimum is met
+    assert_eq!(
+        SNAP_BALANCES
+            .prefix(1)
+            .range(deps.as_ref().storage, None, None, Order::Ascending)
+            .count(),
+        3
+    );
+}
+
+#[test]
+fn expired_deadlines_reject_the_zap() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    let env = mock_env();
+    let expired = Some(Expiration::AtTime(env.block.time.minus_seconds(1)));
+
+    let zap_in = ExecuteMsg::ZapInLiquidity {
+        pool_id: POOL_ID,
+        token_0: TOKEN_0.to_string(),
+        token_1: TOKEN_1.to_string(),
+        lower_tick: -1000,
+        upper_tick: 1000,
+        token_min_amount_0: None,
+        token_min_amount_1: None,
+        routes: vec![],
+        optimal_swap: None,
+        deadline: expired,
+        referral: None,
+    };
+    let zap_in_multi = ExecuteMsg::ZapInLiquidityMulti {
+        pool_id: POOL_ID,
+        token_0: TOKEN_0.to_string(),
+        token_1: TOKEN_1.to_string(),
+        lower_tick: -1000,
+        upper_tick: 1000,
+        token_min_amount_0: None,
+        token_min_amount_1: None,
+        cw20_asset: None,
+        routes: vec![],
+        deadline: Some(Expiration::AtHeight(env.block.height)),
+        referral: None,
+    };
+    let zap_out = ExecuteMsg::ZapOutLiquidity {
+        position_id: POSITION_ID,
+        routes: vec![],
+        liquidity_amount: None,
+        percent: None,
+        minimum_receive: None,
+        deadline: expired,
+        ibc_return: None,
+        referral: None,
+    };
+
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    for (msg, funds) in [
+        (zap_in, vec![coin(1000, TOKEN_0)]),
+        (zap_in_multi, vec![coin(1000, TOKEN_0)]),
+        (zap_out, vec![]),
+    ] {
+        let err = execute(deps.as_mut(), env.clone(), mock_info(USER, &funds), msg).unwrap_err();
+        assert!(matches!(err, ContractError::DeadlineExceeded));
+    }
+
+    // a deadline still ahead lets the zap through
+    execute(
+        deps.as_mut(),
+        env.clone(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        ExecuteMsg::ZapInLiquidity {
+            pool_id: POOL_ID,
+            token_0: TOKEN_0.to_string(),
+            token_1: TOKEN_1.to_string(),
+            lower_tick: -1000,
+            upper_tick: 1000,
+            token_min_amount_0: None,
+            token_min_amount_1: None,
+            routes: vec![],
+            optimal_swap: None,
+            deadline: Some(Expiration::AtTime(env.block.time.plus_seconds(60))),
+            referral: None,
+        },
+    )
+    .unwrap();
+}
+
+fn harvest_msg() -> ExecuteMsg {
+    ExecuteMsg::HarvestRewards {
+        position_id: POSITION_ID,
+        target_denom: TOKEN_1.to_string(),
+        paths: vec![
+            swap_route(TOKEN_0, TOKEN_1, 0).operations,
+            swap_route(REWARD, TOKEN_1, 0).operations,
+        ],
+        minimum_receive: Some(Uint128::new(290)),
+        deadline: None,
+        ibc_return: None,
+    }
+}
+
+fn rewarded_position_detail() -> FullPositionBreakdown {
+    position_detail(
+        vec![proto_coin(TOKEN_0, 100)],
+        vec![proto_coin(REWARD, 200)],
+    )
+}
+
+#[test]
+fn harvest_rewards_swaps_the_rewards_into_the_target() {
+    let mut deps = setup(&[], rewarded_position_detail());
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        harvest_msg(),
+    )
+    .unwrap();
+    // the position is pulled for the harvest
+    let CosmosMsg::Stargate { type_url, .. } = &res.messages[0].msg else {
+        panic!("expected MsgExec");
+    };
+    assert_eq!(type_url, "/cosmos.authz.v1beta1.MsgExec");
+
+    // the collected rewards pay the zap out fee like a withdrawal
+    set_balances(&mut deps, &[coin(100, TOKEN_0), coin(200, REWARD)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(HARVEST_REWARDS_ID, 1), None),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), Uint128::new(1));
+    assert_eq!(accrued_fee(&deps, REWARD), Uint128::new(2));
+    let swaps: Vec<Coin> = stargate_values(&res, MsgSwapExactAmountIn::TYPE_URL)
+        .into_iter()
+        .map(|value| {
+            let token_in = MsgSwapExactAmountIn::try_from(value).unwrap().token_in;
+            let token_in = token_in.unwrap();
+            coin(token_in.amount.parse().unwrap(), token_in.denom)
+        })
+        .collect();
+    assert_eq!(swaps, vec![coin(99, TOKEN_0), coin(198, REWARD)]);
+
+    // and the position goes back to the user
+    let transfers = stargate_values(&res, MsgTransferPositions::TYPE_URL);
+    assert_eq!(
+        MsgTransferPositions::try_from(transfers[0].clone()).unwrap(),
+        MsgTransferPositions {
+            position_ids: vec![POSITION_ID],
+            sender: MOCK_CONTRACT_ADDR.to_string(),
+            new_owner: USER.to_string(),
+        }
+    );
+    assert_eq!(
+        self_execute_msg(&res),
+        ExecuteMsg::TransferFundsBack {
+            receiver: Addr::unchecked(USER),
+            nonce: 1,
+            minimum_receive: vec![coin(290, TOKEN_1)],
+            ibc_return: None,
+        }
+    );
+}
+
+#[test]
+fn harvest_rewards_of_an_escrowed_position_is_limited_to_its_depositor() {
+    let mut detail = rewarded_position_detail();
+    if let Some(position) = detail.position.as_mut() {
+        position.address = MOCK_CONTRACT_ADDR.to_string();
+    }
+    let mut deps = setup(&[], detail);
+    POSITION_OWNERS
+        .save(deps.as_mut().storage, POSITION_ID, &Addr::unchecked(USER))
+        .unwrap();
+
+    let err = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info("attacker", &[]),
+        harvest_msg(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ContractError::Unauthorized));
+
+    // the depositor harvests and the position stays escrowed
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        harvest_msg(),
+    )
+    .unwrap();
+    set_balances(&mut deps, &[coin(100, TOKEN_0), coin(200, REWARD)]);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(HARVEST_REWARDS_ID, 1), None),
+    )
+    .unwrap();
+    assert!(stargate_values(&res, MsgTransferPositions::TYPE_URL).is_empty());
+    assert_eq!(
+        POSITION_OWNERS
+            .load(deps.as_ref().storage, POSITION_ID)
+            .unwrap(),
+        USER
+    );
+}
+
+fn swapped_coins(res: &Response) -> Vec<Coin> {
+    stargate_values(res, MsgSwapExactAmountIn::TYPE_URL)
+        .into_iter()
+        .map(|value| {
+            let token_in = MsgSwapExactAmountIn::try_from(value)
+                .unwrap()
+                .token_in
+                .unwrap();
+            coin(token_in.amount.parse().unwrap(), token_in.denom)
+        })
+        .collect()
+}
+
+#[test]
+fn simulate_zap_in_matches_the_zap_in() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    set_max_referral_bps(&mut deps, 100);
+    let routes = vec![swap_route(TOKEN_0, TOKEN_1, 400)];
+
+    let simulation: SimulateZapInResponse = from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::SimulateZapIn {
+                asset_in: Asset::new(&deps.api, TOKEN_0, Uint128::new(1000)),
+                pool_id: POOL_ID,
+                token_0: TOKEN_0.to_string(),
+                token_1: TOKEN_1.to_string(),
+                lower_tick: -1000,
+                upper_tick: 1000,
+                token_min_amount_0: None,
+                token_min_amount_1: None,
+                routes: routes.clone(),
+                optimal_swap: None,
+                referral: referral(100),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(simulation.protocol_fee, coin(10, TOKEN_0));
+    assert_eq!(simulation.referral_fee, coin(9, TOKEN_0));
+    assert_eq!(
+        simulation.routes,
+        vec![RouteSimulation {
+            token_in: TOKEN_0.to_string(),
+            offer_amount: Uint128::new(400),
+            token_out: TOKEN_1.to_string(),
+            return_amount: Uint128::new(396),
+        }]
+    );
+
+    set_balances(&mut deps, &[coin(1000, TOKEN_0)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[coin(1000, TOKEN_0)]),
+        zap_in_msg_with_referral(routes, referral(100)),
+    )
+    .unwrap();
+    assert_eq!(accrued_fee(&deps, TOKEN_0), simulation.protocol_fee.amount);
+    assert_eq!(
+        bank_sends(&res),
+        vec![("referrer".to_string(), vec![simulation.referral_fee])]
+    );
+    assert_eq!(swapped_coins(&res), vec![coin(400, TOKEN_0)]);
+
+    // the swap returns the simulated amount
+    set_balances(&mut deps, &[coin(591, TOKEN_0), coin(396, TOKEN_1)]);
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        self_execute_msg(&res),
+    )
+    .unwrap();
+    let create = stargate_values(&res, MsgCreatePosition::TYPE_URL);
+    let create = MsgCreatePosition::try_from(create[0].clone()).unwrap();
+    let tokens_provided: Vec<Coin> = create
+        .tokens_provided
+        .into_iter()
+        .map(|c| coin(c.amount.parse().unwrap(), c.denom))
+        .collect();
+    assert_eq!(tokens_provided, simulation.tokens_provided);
+
+    // the position takes what the simulation does not refund
+    let refund = |denom: &str| {
+        simulation
+            .refunds
+            .iter()
+            .find(|c| c.denom == denom)
+            .map(|c| c.amount.u128())
+            .unwrap_or_default()
+    };
+    set_balances(
+        &mut deps,
+        &[
+            coin(10 + refund(TOKEN_0), TOKEN_0),
+            coin(refund(TOKEN_1), TOKEN_1),
+        ],
+    );
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(
+            encode_reply_id(CREATE_POSITION_ID, 1),
+            Some(
+                MsgCreatePositionResponse {
+                    position_id: 8,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+        ),
+    )
+    .unwrap();
+    let refunds: Vec<Coin> = bank_sends(&res)
+        .into_iter()
+        .flat_map(|(to_address, amount)| {
+            assert_eq!(to_address, USER);
+            amount
+        })
+        .collect();
+    assert_eq!(refunds, simulation.refunds);
+    assert!(!refunds.is_empty());
+}
+
+#[test]
+fn simulate_zap_out_matches_the_zap_out() {
+    let mut deps = setup(&[], position_detail(vec![], vec![]));
+    set_max_referral_bps(&mut deps, 100);
+    let routes = vec![swap_route(TOKEN_0, TOKEN_1, 500)];
+
+    let simulation: SimulateZapOutResponse = from_json(
+        query(
+            deps.as_ref(),
+            mock_env(),
+            QueryMsg::SimulateZapOut {
+                position_id: POSITION_ID,
+                routes: routes.clone(),
+                liquidity_amount: None,
+                percent: Some(Decimal::percent(50)),
+                minimum_receive: None,
+                referral: referral(100),
+            },
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        simulation.withdrawn,
+        vec![coin(500, TOKEN_0), coin(500, TOKEN_1)]
+    );
+    assert_eq!(simulation.protocol_fees, vec![coin(5, TOKEN_0)]);
+    assert_eq!(simulation.referral_fees, vec![coin(4, TOKEN_0)]);
+    assert_eq!(simulation.refunds, vec![coin(986, TOKEN_1)]);
+
+    execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(USER, &[]),
+        ExecuteMsg::ZapOutLiquidity {
+            position_id: POSITION_ID,
+            routes,
+            liquidity_amount: None,
+            percent: Some(Decimal::percent(50)),
+            minimum_receive: None,
+            deadline: None,
+            ibc_return: None,
+            referral: referral(100),
+        },
+    )
+    .unwrap();
+
+    set_balances(&mut deps, &simulation.withdrawn);
+    let res = reply(
+        deps.as_mut(),
+        mock_env(),
+        reply_ok(encode_reply_id(WITHDRAW_POSITION_ID, 1), None),
+    )
+    .unwrap();
+    assert_eq!(
+        accrued_fee(&deps, TOKEN_0),
+        simulation.protocol_fees[0].amount
+    );
+    assert_eq!(
+        bank_sends(&res),
+        vec![("referrer".to_string(), simulation.referral_fees.clone())]
+    );
+    let swapped = swapped_coins(&res);
+    assert_eq!(swapped, vec![coin(491, TOKEN_0)]);
+
+    // the swap returns the simulated amount
+    let return_amount = simulation.routes[0].return_amount.u128();
+    set_balances(
+        &mut deps,
+        &[coin(5, TOKEN_0), coin(500 + return_amount, TOKEN_1)],
+    );
+    let res = execute(
+        deps.as_mut(),
+        mock_env(),
+        mock_info(MOCK_CONTRACT_ADDR, &[]),
+        self_execute_msg(&res),
+    )
+    .unwrap();
+    assert_eq!(
+        bank_sends(&res),
+        vec![(USER.to_string(), simulation.refunds)]
+    );
+}
@@ -1,32 +1,50 @@
 use std::str::FromStr;
 
 use cosmwasm_std::{
-    coin, wasm_execute, CosmosMsg, Decimal256, DepsMut, Env, MessageInfo, Response, SubMsg, Uint128,
+    coin, to_json_binary, wasm_execute, Coin, CosmosMsg, Decimal, Decimal256, DepsMut, Env,
+    MessageInfo, Response, StdError, SubMsg, Uint128, WasmMsg,
 };
-use cw_utils::one_coin;
+use cw20::{Cw20Coin, Cw20ExecuteMsg};
+use cw_utils::{one_coin, Expiration};
 use osmosis_std::types::{
     cosmos::base::v1beta1::Coin as OsmosisCoin,
-    osmosis::concentratedliquidity::v1beta1::{
-        ConcentratedliquidityQuerier, MsgCreatePosition, MsgWithdrawPosition,
+    osmosis::{
+        concentratedliquidity::v1beta1::{
+            ConcentratedliquidityQuerier, MsgAddToPosition, MsgCollectIncentives,
+            MsgCollectSpreadRewards, MsgCreatePosition, MsgWithdrawPosition,
+        },
+        gamm::v1beta1::{GammQuerier, MsgExitPool, MsgJoinPool, MsgJoinSwapExternAmountIn},
     },
 };
 use zapper::{
-    asset::{get_current_asset_available, Asset},
+    asset::Asset,
     error::ZapperError,
-    swap::Route,
+    swap::{Route, SwapOperation},
 };
 
 use crate::{
-    contract::{CREATE_POSITION_ID, WITHDRAW_POSITION_ID},
+    contract::{
+        encode_reply_id, ADD_TO_POSITION_ID, CREATE_POSITION_ID, EXIT_POOL_ID, HARVEST_REWARDS_ID,
+        JOIN_POOL_ID, MIGRATE_POSITION_ID, WITHDRAW_POSITION_ID,
+    },
     error::{ContractError, ContractResult},
-    helper::create_osmosis_swap_msg,
-    msg::ExecuteMsg,
+    helper::{
+        assert_deadline, build_optimal_swap_routes, claimable_rewards, create_collect_rewards_msgs,
+        create_osmosis_swap_msg, deduct_protocol_fee, deduct_referral_fee, escrow_position,
+        gamm_share_denom, parse_gamm_share_denom, query_concentrated_pool, query_gamm_pool_denoms,
+        resolve_withdraw_liquidity, validate_ibc_return, validate_referral, validate_reward_routes,
+        validate_target_paths, validate_zap_in_routes,
+    },
+    msg::{ConverterHookMsg, Cw20HookMsg, ExecuteMsg},
     state::{
-        snapshot_balances, PendingPosition, PendingZapOut, PENDING_POSITION, PENDING_ZAP_OUT,
-        PROTOCOL_FEE, SNAP_BALANCES,
+        get_available_balance, next_nonce, snapshot_balances, FeeDirection, IbcReturn,
+        PendingAddToPosition, PendingJoinPool, PendingMigration, PendingPosition, PendingZapOut,
+        Referral, ZapOutTarget, CW20_CONVERTERS, PENDING_ADD_TO_POSITIONS, PENDING_JOIN_POOLS,
+        PENDING_MIGRATIONS, PENDING_POSITIONS, PENDING_ZAP_OUTS, SNAP_BALANCES,
     },
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn zap_in_liquidity(
     deps: DepsMut,
     env: Env,
@@ -38,32 +56,74 @@ pub fn zap_in_liquidity(
     upper_tick: i64,
     token_min_amount_0: Option<Uint128>,
     token_min_amount_1: Option<Uint128>,
-    asset_in: Option<Asset>,
     routes: Vec<Route>,
+    optimal_swap: Option<bool>,
+    deadline: Option<Expiration>,
+    referral: Option<Referral>,
 ) -> ContractResult<Response> {
-    // Validate and unwrap the sent asset
-    let asset_in = match asset_in {
-        Some(sent_asset) => {
-            sent_asset.validate(&deps, &env, &info)?;
-            sent_asset
-        }
-        None => one_coin(&info)?.into(),
-    };
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    let asset_in: Asset = one_coin(&info)?.into();
 
     // init messages and submessages
     let mut msgs: Vec<CosmosMsg> = vec![];
 
-    let mut amount_after_fee = asset_in.amount();
-    // handle deduct zap in fee
-    if let Some(protocol_fee) = PROTOCOL_FEE.may_load(deps.storage)? {
-        if !protocol_fee.percent.is_zero() {
-            let fee_amount = asset_in.amount() * protocol_fee.percent;
-            amount_after_fee -= fee_amount;
-            // transfer fee to fee_receiver
-            msgs.push(asset_in.transfer_amount(fee_amount, protocol_fee.fee_receiver.as_str()));
+    // snapshot position tokens and the sent asset before the fee accrues, excluding the
+    // sent amount, so that whatever the routes leave of the sent asset is refunded
+    let nonce = next_nonce(deps.storage)?;
+    for denom in [token_0.as_str(), token_1.as_str(), asset_in.denom()] {
+        if SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            continue;
+        }
+        let mut balance = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        if asset_in.denom() == denom {
+            balance.sub(asset_in.amount())?;
         }
+        SNAP_BALANCES.save(deps.storage, (nonce, denom), &balance.amount())?;
     }
 
+    // handle deduct zap in fee
+    let amount_after_fee = deduct_protocol_fee(
+        deps.storage,
+        pool_id,
+        FeeDirection::ZapIn,
+        &asset_in,
+        asset_in.amount(),
+    )?;
+    let amount_after_fee = deduct_referral_fee(
+        deps.storage,
+        referral.as_ref(),
+        &asset_in,
+        amount_after_fee,
+        &mut msgs,
+    )?;
+
+    validate_zap_in_routes(&routes, asset_in.denom())?;
+
+    // let the contract decide how much to swap into each token
+    let routes = if optimal_swap.unwrap_or(false) {
+        build_optimal_swap_routes(
+            &deps.as_ref(),
+            pool_id,
+            &token_0,
+            &token_1,
+            lower_tick,
+            upper_tick,
+            asset_in.denom(),
+            amount_after_fee,
+            &routes,
+        )?
+    } else {
+        routes
+    };
+
     // validate asset_in and routes
     let total_swap_amount: Uint128 = routes
         .iter()
@@ -75,29 +135,206 @@ pub fn zap_in_liquidity(
         return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
     }
 
-    let mut balance_0 =
-        get_current_asset_available(deps.api, &deps.querier, &env.contract.address, &token_0)?;
-    let mut balance_1 =
-        get_current_asset_available(deps.api, &deps.querier, &env.contract.address, &token_1)?;
-
-    if asset_in.denom() == token_0 {
-        balance_0.sub(asset_in.amount())?;
+    for route in &routes {
+        let swap_msg = create_osmosis_swap_msg(
+            env.contract.address.to_string(),
+            coin(route.offer_amount.into(), asset_in.denom()),
+            route.operations.clone(),
+            route.minimum_receive,
+        )?;
+        msgs.push(swap_msg);
     }
-    if asset_in.denom() == token_1 {
-        balance_1.sub(asset_in.amount())?;
-    };
 
-    SNAP_BALANCES.save(deps.storage, &token_0, &balance_0.amount())?;
-    SNAP_BALANCES.save(deps.storage, &token_1, &balance_1.amount())?;
+    msgs.push(
+        wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::CreatePosition {
+                pool_id,
+                token_0: token_0.clone(),
+                token_1: token_1.clone(),
+                lower_tick,
+                upper_tick,
+                token_min_amount_0,
+                token_min_amount_1,
+                nonce,
+            },
+            vec![],
+        )?
+        .into(),
+    );
 
-    for i in 0..routes.len() {
-        let swap_msg = create_osmosis_swap_msg(
+    // store pending position
+    PENDING_POSITIONS.save(
+        deps.storage,
+        nonce,
+        &PendingPosition {
+            receiver: info.sender,
+            pool_id,
+            token_0,
+            token_1,
+        },
+    )?;
+    Ok(Response::new().add_messages(msgs))
+}
+
+// Zaps all native funds, plus an optional CW20 converted to its native counterpart, into
+// a concentrated position. Routes draw from any sent asset by token_in, and everything
+// not used by the position is refunded.
+#[allow(clippy::too_many_arguments)]
+pub fn zap_in_liquidity_multi(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    pool_id: u64,
+    token_0: String,
+    token_1: String,
+    lower_tick: i64,
+    upper_tick: i64,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    cw20_asset: Option<Cw20Coin>,
+    routes: Vec<Route>,
+    deadline: Option<Expiration>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    // the cw20 is pulled into its converter first, the zap continues with the sent funds
+    // and the converted coin once the conversion is done
+    if let Some(cw20_coin) = cw20_asset {
+        let cw20_address = deps.api.addr_validate(&cw20_coin.address)?;
+        let converter = CW20_CONVERTERS
+            .may_load(deps.storage, &cw20_address)?
+            .ok_or(ContractError::AssetNotNative)?;
+        if cw20_coin.amount.is_zero() {
+            return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+        }
+
+        let nonce = next_nonce(deps.storage)?;
+        snapshot_balances(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env,
+            nonce,
+            &converter.native_denom,
+        )?;
+
+        let convert_msg = WasmMsg::Execute {
+            contract_addr: cw20_address.to_string(),
+            msg: to_json_binary(&Cw20ExecuteMsg::SendFrom {
+                owner: info.sender.to_string(),
+                contract: converter.contract.to_string(),
+                amount: cw20_coin.amount,
+                msg: to_json_binary(&ConverterHookMsg::Convert {})?,
+            })?,
+            funds: vec![],
+        };
+        let zap_in_msg = wasm_execute(
             env.contract.address.to_string(),
-            coin(routes[i].offer_amount.into(), asset_in.denom()),
-            routes[i].operations.clone(),
-            None,
+            &ExecuteMsg::ZapInConverted {
+                receiver: info.sender,
+                nonce,
+                native_denom: converter.native_denom,
+                msg: Cw20HookMsg::ZapInLiquidityMulti {
+                    pool_id,
+                    token_0,
+                    token_1,
+                    lower_tick,
+                    upper_tick,
+                    token_min_amount_0,
+                    token_min_amount_1,
+                    routes,
+                    deadline,
+                    referral,
+                },
+            },
+            info.funds,
         )?;
-        msgs.push(swap_msg);
+
+        return Ok(Response::new()
+            .add_message(convert_msg)
+            .add_message(zap_in_msg));
+    }
+
+    // init messages and submessages
+    let mut msgs: Vec<CosmosMsg> = vec![];
+
+    let assets: Vec<Asset> = info.funds.iter().cloned().map(Asset::from).collect();
+    if assets.is_empty() || assets.iter().any(|asset| asset.amount().is_zero()) {
+        return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+    }
+
+    // every route must draw from a sent asset
+    if routes
+        .iter()
+        .any(|route| !assets.iter().any(|asset| asset.denom() == route.token_in))
+    {
+        return Err(ContractError::CoinInDenomMismatch);
+    }
+
+    // snapshot every denom of the zap, excluding the sent native funds
+    let nonce = next_nonce(deps.storage)?;
+    for denom in assets
+        .iter()
+        .map(|asset| asset.denom())
+        .chain([token_0.as_str(), token_1.as_str()])
+    {
+        if SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            continue;
+        }
+        let mut balance = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        if let Some(fund) = info.funds.iter().find(|fund| fund.denom == denom) {
+            balance.sub(fund.amount)?;
+        }
+        SNAP_BALANCES.save(deps.storage, (nonce, denom), &balance.amount())?;
+    }
+
+    for asset in &assets {
+        // handle deduct zap in fee
+        let amount_after_fee = deduct_protocol_fee(
+            deps.storage,
+            pool_id,
+            FeeDirection::ZapIn,
+            asset,
+            asset.amount(),
+        )?;
+        let amount_after_fee = deduct_referral_fee(
+            deps.storage,
+            referral.as_ref(),
+            asset,
+            amount_after_fee,
+            &mut msgs,
+        )?;
+
+        let asset_routes: Vec<Route> = routes
+            .iter()
+            .filter(|route| route.token_in == asset.denom())
+            .cloned()
+            .collect();
+        validate_zap_in_routes(&asset_routes, asset.denom())?;
+
+        let total_swap_amount: Uint128 = asset_routes.iter().map(|route| route.offer_amount).sum();
+        if total_swap_amount.gt(&amount_after_fee) {
+            return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+        }
+
+        for route in asset_routes {
+            let swap_msg = create_osmosis_swap_msg(
+                env.contract.address.to_string(),
+                coin(route.offer_amount.into(), asset.denom()),
+                route.operations,
+                route.minimum_receive,
+            )?;
+            msgs.push(swap_msg);
+        }
     }
 
     msgs.push(
@@ -111,6 +348,7 @@ pub fn zap_in_liquidity(
                 upper_tick,
                 token_min_amount_0,
                 token_min_amount_1,
+                nonce,
             },
             vec![],
         )?
@@ -118,8 +356,9 @@ pub fn zap_in_liquidity(
     );
 
     // store pending position
-    PENDING_POSITION.save(
+    PENDING_POSITIONS.save(
         deps.storage,
+        nonce,
         &PendingPosition {
             receiver: info.sender,
             pool_id,
@@ -130,6 +369,7 @@ pub fn zap_in_liquidity(
     Ok(Response::new().add_messages(msgs))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_position(
     deps: DepsMut,
     env: Env,
@@ -141,20 +381,31 @@ pub fn create_position(
     upper_tick: i64,
     token_min_amount_0: Option<Uint128>,
     token_min_amount_1: Option<Uint128>,
+    nonce: u64,
 ) -> ContractResult<Response> {
     if info.sender != env.contract.address {
         return Err(ContractError::Unauthorized {});
     }
 
     //  Recheck the balance of tokenX and tokenY in this contract
-    let x_amount_before = SNAP_BALANCES.load(deps.storage, &token_0)?;
-    let y_amount_before = SNAP_BALANCES.load(deps.storage, &token_1)?;
+    let x_amount_before = SNAP_BALANCES.load(deps.storage, (nonce, &token_0))?;
+    let y_amount_before = SNAP_BALANCES.load(deps.storage, (nonce, &token_1))?;
 
     //  Minus with the previous balance of tokenX and tokenY snap in state
-    let x_amount_after =
-        get_current_asset_available(deps.api, &deps.querier, &env.contract.address, &token_0)?;
-    let y_amount_after =
-        get_current_asset_available(deps.api, &deps.querier, &env.contract.address, &token_1)?;
+    let x_amount_after = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &token_0,
+    )?;
+    let y_amount_after = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &token_1,
+    )?;
     let x_amount = x_amount_after.amount() - x_amount_before;
     let y_amount = y_amount_after.amount() - y_amount_before;
 
@@ -184,37 +435,503 @@ pub fn create_position(
     }
     .into();
 
-    Ok(
-        Response::new()
-            .add_submessage(SubMsg::reply_on_success(msg_create_pos, CREATE_POSITION_ID)),
-    )
+    Ok(Response::new().add_submessage(SubMsg::reply_on_success(
+        msg_create_pos,
+        encode_reply_id(CREATE_POSITION_ID, nonce),
+    )))
 }
 
-// Ensure this position transfer to contract first
+// Swaps the sent coin into the position tokens and adds them to the position, which
+// is pulled from the sender through authz or must already be escrowed by the sender
+#[allow(clippy::too_many_arguments)]
+pub fn zap_into_position(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    routes: Vec<Route>,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    deadline: Option<Expiration>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    let asset_in: Asset = one_coin(&info)?.into();
+
+    let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let position = position_detail
+        .position
+        .as_ref()
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let pool = query_concentrated_pool(&deps.as_ref(), position.pool_id)?;
+    let (token_0, token_1) = (pool.token0, pool.token1);
+
+    // init messages and submessages
+    let mut msgs: Vec<CosmosMsg> = vec![];
+
+    // the position must be owned by the contract before it is added to
+    if let Some(escrow_msg) = escrow_position(deps.storage, &env, &info.sender, position)? {
+        msgs.push(escrow_msg);
+    }
+
+    // snapshot the position tokens and the sent coin without the sent amount, then the
+    // claimed rewards, before the fee accrues
+    let nonce = next_nonce(deps.storage)?;
+    for denom in [token_0.as_str(), token_1.as_str(), asset_in.denom()] {
+        if SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            continue;
+        }
+        let mut balance = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        if asset_in.denom() == denom {
+            balance.sub(asset_in.amount())?;
+        }
+        SNAP_BALANCES.save(deps.storage, (nonce, denom), &balance.amount())?;
+    }
+    for reward in position_detail
+        .claimable_spread_rewards
+        .iter()
+        .chain(position_detail.claimable_incentives.iter())
+    {
+        if !SNAP_BALANCES.has(deps.storage, (nonce, &reward.denom)) {
+            snapshot_balances(
+                deps.api,
+                &deps.querier,
+                deps.storage,
+                &env,
+                nonce,
+                &reward.denom,
+            )?;
+        }
+    }
+
+    // handle deduct zap in fee
+    let amount_after_fee = deduct_protocol_fee(
+        deps.storage,
+        position.pool_id,
+        FeeDirection::ZapIn,
+        &asset_in,
+        asset_in.amount(),
+    )?;
+    let amount_after_fee = deduct_referral_fee(
+        deps.storage,
+        referral.as_ref(),
+        &asset_in,
+        amount_after_fee,
+        &mut msgs,
+    )?;
+
+    validate_zap_in_routes(&routes, asset_in.denom())?;
+    let total_swap_amount: Uint128 = routes.iter().map(|route| route.offer_amount).sum();
+    if total_swap_amount.gt(&amount_after_fee) {
+        return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+    }
+
+    for route in routes {
+        let swap_msg = create_osmosis_swap_msg(
+            env.contract.address.to_string(),
+            coin(route.offer_amount.into(), asset_in.denom()),
+            route.operations,
+            route.minimum_receive,
+        )?;
+        msgs.push(swap_msg);
+    }
+
+    msgs.push(
+        wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::AddToPosition {
+                position_id,
+                token_0,
+                token_1,
+                token_min_amount_0,
+                token_min_amount_1,
+                nonce,
+            },
+            vec![],
+        )?
+        .into(),
+    );
+
+    PENDING_ADD_TO_POSITIONS.save(
+        deps.storage,
+        nonce,
+        &PendingAddToPosition {
+            receiver: info.sender,
+            position_id,
+        },
+    )?;
+    Ok(Response::new().add_messages(msgs))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn add_to_position(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    token_0: String,
+    token_1: String,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    nonce: u64,
+) -> ContractResult<Response> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    // only what the swaps brought in is added to the position
+    let amount_0 = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &token_0,
+    )?
+    .amount()
+    .checked_sub(SNAP_BALANCES.load(deps.storage, (nonce, &token_0))?)?;
+    let amount_1 = get_available_balance(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env.contract.address,
+        &token_1,
+    )?
+    .amount()
+    .checked_sub(SNAP_BALANCES.load(deps.storage, (nonce, &token_1))?)?;
+
+    let msg_add_to_position: CosmosMsg = MsgAddToPosition {
+        position_id,
+        sender: env.contract.address.to_string(),
+        amount0: amount_0.to_string(),
+        amount1: amount_1.to_string(),
+        token_min_amount0: token_min_amount_0.unwrap_or_default().to_string(),
+        token_min_amount1: token_min_amount_1.unwrap_or_default().to_string(),
+    }
+    .into();
+
+    Ok(Response::new().add_submessage(SubMsg::reply_on_success(
+        msg_add_to_position,
+        encode_reply_id(ADD_TO_POSITION_ID, nonce),
+    )))
+}
+
+// Zaps into a GAMM balancer or stableswap pool, the LP shares are sent to the sender
+#[allow(clippy::too_many_arguments)]
+pub fn zap_in_gamm_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    pool_id: u64,
+    routes: Vec<Route>,
+    share_out_min_amount: Option<Uint128>,
+    deadline: Option<Expiration>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    let asset_in: Asset = one_coin(&info)?.into();
+    let denoms = query_gamm_pool_denoms(&deps.as_ref(), pool_id)?;
+
+    // init messages and submessages
+    let mut msgs: Vec<CosmosMsg> = vec![];
+
+    // snapshot pool tokens and the sent asset, excluding the sent amount, and LP shares
+    // before the fee accrues, so that whatever the routes leave of the sent asset is refunded
+    let nonce = next_nonce(deps.storage)?;
+    let mut snapshot_denoms: Vec<&str> = denoms.iter().map(String::as_str).collect();
+    if !snapshot_denoms.contains(&asset_in.denom()) {
+        snapshot_denoms.push(asset_in.denom());
+    }
+    for denom in snapshot_denoms {
+        let mut balance = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        if asset_in.denom() == denom {
+            balance.sub(asset_in.amount())?;
+        }
+        SNAP_BALANCES.save(deps.storage, (nonce, denom), &balance.amount())?;
+    }
+    snapshot_balances(
+        deps.api,
+        &deps.querier,
+        deps.storage,
+        &env,
+        nonce,
+        &gamm_share_denom(pool_id),
+    )?;
+
+    // handle deduct zap in fee
+    let amount_after_fee = deduct_protocol_fee(
+        deps.storage,
+        pool_id,
+        FeeDirection::ZapIn,
+        &asset_in,
+        asset_in.amount(),
+    )?;
+    let amount_after_fee = deduct_referral_fee(
+        deps.storage,
+        referral.as_ref(),
+        &asset_in,
+        amount_after_fee,
+        &mut msgs,
+    )?;
+
+    // validate asset_in and routes
+    validate_zap_in_routes(&routes, asset_in.denom())?;
+    let total_swap_amount: Uint128 = routes.iter().map(|route| route.offer_amount).sum();
+    if total_swap_amount.gt(&amount_after_fee) {
+        return Err(ContractError::Zapper(ZapperError::InvalidFund {}));
+    }
+
+    for route in &routes {
+        let swap_msg = create_osmosis_swap_msg(
+            env.contract.address.to_string(),
+            coin(route.offer_amount.into(), asset_in.denom()),
+            route.operations.clone(),
+            route.minimum_receive,
+        )?;
+        msgs.push(swap_msg);
+    }
+
+    msgs.push(
+        wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::JoinGammPool {
+                pool_id,
+                share_out_min_amount,
+                nonce,
+            },
+            vec![],
+        )?
+        .into(),
+    );
+
+    // store pending join pool
+    PENDING_JOIN_POOLS.save(
+        deps.storage,
+        nonce,
+        &PendingJoinPool {
+            receiver: info.sender,
+            pool_id,
+            denoms,
+        },
+    )?;
+    Ok(Response::new().add_messages(msgs))
+}
+
+pub fn join_gamm_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    pool_id: u64,
+    share_out_min_amount: Option<Uint128>,
+    nonce: u64,
+) -> ContractResult<Response> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized {});
+    }
+
+    // tokens received from the swaps and what is left of the sent asset
+    let pending_join_pool = PENDING_JOIN_POOLS.load(deps.storage, nonce)?;
+    let mut tokens_in: Vec<OsmosisCoin> = vec![];
+    for denom in &pending_join_pool.denoms {
+        let amount_before = SNAP_BALANCES.load(deps.storage, (nonce, denom))?;
+        let amount_after = get_available_balance(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env.contract.address,
+            denom,
+        )?;
+        let amount = amount_after.amount().checked_sub(amount_before)?;
+        if !amount.is_zero() {
+            tokens_in.push(OsmosisCoin {
+                denom: denom.clone(),
+                amount: amount.to_string(),
+            });
+        }
+    }
+
+    let share_out_min_amount = share_out_min_amount.unwrap_or_default();
+    let join_pool_msg: CosmosMsg = match tokens_in.len() {
+        0 => return Err(ContractError::Zapper(ZapperError::InvalidFund {})),
+        // single sided join, the pool swaps internally
+        1 => MsgJoinSwapExternAmountIn {
+            sender: env.contract.address.to_string(),
+            pool_id,
+            token_in: tokens_in.pop(),
+            share_out_min_amount: share_out_min_amount.to_string(),
+        }
+        .into(),
+        // balanced join, leftovers are refunded in the reply
+        _ => {
+            let shares_out = GammQuerier::new(&deps.querier)
+                .calc_join_pool_no_swap_shares(pool_id, tokens_in.clone())?
+                .shares_out;
+            if Uint128::from_str(&shares_out)? < share_out_min_amount {
+                return Err(ContractError::JoinPoolError(format!(
+                    "shares out {} is less than {}",
+                    shares_out, share_out_min_amount
+                )));
+            }
+            MsgJoinPool {
+                sender: env.contract.address.to_string(),
+                pool_id,
+                share_out_amount: shares_out,
+                token_in_maxs: tokens_in,
+            }
+            .into()
+        }
+    };
+
+    Ok(Response::new().add_submessage(SubMsg::reply_on_success(
+        join_pool_msg,
+        encode_reply_id(JOIN_POOL_ID, nonce),
+    )))
+}
+
+// The position is pulled from the sender through authz, or must already be escrowed by the sender
+#[allow(clippy::too_many_arguments)]
 pub fn zap_out_liquidity(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     position_id: u64,
     routes: Vec<Route>,
+    liquidity_amount: Option<Decimal256>,
+    percent: Option<Decimal>,
+    minimum_receive: Option<Vec<Coin>>,
+    deadline: Option<Expiration>,
+    ibc_return: Option<IbcReturn>,
+    referral: Option<Referral>,
 ) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    zap_out_position(
+        deps,
+        env,
+        info,
+        position_id,
+        liquidity_amount,
+        percent,
+        routes,
+        None,
+        minimum_receive.unwrap_or_default(),
+        ibc_return,
+        referral,
+    )
+}
+
+// Withdraws the whole position and swaps everything received, including claimed
+// rewards, into target_denom along the given paths
+#[allow(clippy::too_many_arguments)]
+pub fn zap_out_to(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    target_denom: String,
+    paths: Vec<Vec<SwapOperation>>,
+    minimum_receive: Option<Uint128>,
+    deadline: Option<Expiration>,
+    ibc_return: Option<IbcReturn>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+
+    validate_target_paths(&paths, &target_denom)?;
+
+    let minimum_receive = minimum_receive
+        .map(|amount| vec![coin(amount.u128(), &target_denom)])
+        .unwrap_or_default();
+
+    zap_out_position(
+        deps,
+        env,
+        info,
+        position_id,
+        None,
+        None,
+        vec![],
+        Some(ZapOutTarget {
+            denom: target_denom,
+            paths,
+        }),
+        minimum_receive,
+        ibc_return,
+        referral,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn zap_out_position(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    liquidity_amount: Option<Decimal256>,
+    percent: Option<Decimal>,
+    routes: Vec<Route>,
+    target: Option<ZapOutTarget>,
+    minimum_receive: Vec<Coin>,
+    ibc_return: Option<IbcReturn>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    validate_ibc_return(ibc_return.as_ref())?;
+
     // query positions
     let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
         .position_by_id(position_id)?
         .position
-        .unwrap();
+        .ok_or_else(|| StdError::not_found("position"))?;
 
     // snapshot token 0 & token 1
-    // TODO: dont use unwarp()
-    let token_0 = position_detail.asset0.unwrap().denom;
-    let token_1 = position_detail.asset1.unwrap().denom;
-    let position = position_detail.position.unwrap();
+    let token_0 = position_detail
+        .asset0
+        .ok_or_else(|| StdError::not_found("position"))?
+        .denom;
+    let token_1 = position_detail
+        .asset1
+        .ok_or_else(|| StdError::not_found("position"))?
+        .denom;
+    let position = position_detail
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
 
-    // clear snapshot balances first
-    SNAP_BALANCES.clear(deps.storage);
+    // only the depositor of the position can zap it out
+    let escrow_msg = escrow_position(deps.storage, &env, &info.sender, &position)?;
 
-    snapshot_balances(deps.api, &deps.querier, deps.storage, &env, &token_0)?;
-    snapshot_balances(deps.api, &deps.querier, deps.storage, &env, &token_1)?;
+    // resolve how much liquidity to withdraw
+    let position_liquidity = Decimal256::from_str(&position.liquidity)?;
+    let withdraw_liquidity =
+        resolve_withdraw_liquidity(position_liquidity, liquidity_amount, percent)?;
+    let residual_position_id = if withdraw_liquidity < position_liquidity {
+        Some(position_id)
+    } else {
+        None
+    };
+
+    let nonce = next_nonce(deps.storage)?;
+    snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, &token_0)?;
+    snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, &token_1)?;
 
     // snapshot incentives
     for incentive in &position_detail.claimable_incentives {
@@ -223,23 +940,321 @@ pub fn zap_out_liquidity(
             &deps.querier,
             deps.storage,
             &env,
+            nonce,
             &incentive.denom,
         )?;
     }
 
     // snapshot token out of zap out
-    for route in &routes {
-        let ops = route.operations.last();
-        if let Some(ops) = ops {
-            snapshot_balances(deps.api, &deps.querier, deps.storage, &env, &ops.denom_out)?;
-        }
+    let denoms_out = routes
+        .iter()
+        .filter_map(|route| route.operations.last().map(|ops| &ops.denom_out))
+        .chain(target.iter().map(|target| &target.denom));
+    for denom_out in denoms_out {
+        snapshot_balances(
+            deps.api,
+            &deps.querier,
+            deps.storage,
+            &env,
+            nonce,
+            denom_out,
+        )?;
     }
 
-    PENDING_ZAP_OUT.save(
+    PENDING_ZAP_OUTS.save(
         deps.storage,
+        nonce,
         &PendingZapOut {
             receiver: info.sender,
+            pool_id: position.pool_id,
             routes,
+            target,
+            minimum_receive,
+            position_id: Some(position_id),
+            residual_position_id,
+            ibc_return,
+            referral,
+        },
+    )?;
+
+    // scale 1e18
+    let liquidity_amount =
+        (withdraw_liquidity * Decimal256::from_str("1000000000000000000")?).to_string();
+
+    let withdraw_position_msg = MsgWithdrawPosition {
+        position_id,
+        sender: env.contract.address.to_string(),
+        liquidity_amount,
+    };
+
+    Ok(Response::new()
+        .add_messages(escrow_msg)
+        .add_submessage(SubMsg::reply_on_success(
+            withdraw_position_msg,
+            encode_reply_id(WITHDRAW_POSITION_ID, nonce),
+        )))
+}
+
+// Collects the rewards of a position, swaps them into the position tokens and adds
+// everything back to the position through the add to position reply chain
+#[allow(clippy::too_many_arguments)]
+pub fn auto_compound(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    routes: Vec<Route>,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    deadline: Option<Expiration>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+
+    let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let position = position_detail
+        .position
+        .as_ref()
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let pool = query_concentrated_pool(&deps.as_ref(), position.pool_id)?;
+    let (token_0, token_1) = (pool.token0, pool.token1);
+
+    let rewards = claimable_rewards(&position_detail)?;
+    validate_reward_routes(&routes, &rewards, &[&token_0, &token_1])?;
+
+    // init messages and submessages
+    let mut msgs: Vec<CosmosMsg> = vec![];
+
+    // rewards can only be collected by the contract once the position is escrowed
+    if let Some(escrow_msg) = escrow_position(deps.storage, &env, &info.sender, position)? {
+        msgs.push(escrow_msg);
+    }
+
+    // snapshot position tokens & rewards
+    let nonce = next_nonce(deps.storage)?;
+    for denom in [&token_0, &token_1]
+        .into_iter()
+        .chain(rewards.iter().map(|reward| &reward.denom))
+    {
+        if !SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, denom)?;
+        }
+    }
+
+    msgs.extend(create_collect_rewards_msgs(&env, position_id));
+
+    for route in routes {
+        let reward = Asset::new(deps.api, &route.token_in, route.offer_amount);
+        let amount_to_swap = deduct_protocol_fee(
+            deps.storage,
+            position.pool_id,
+            FeeDirection::ZapIn,
+            &reward,
+            route.offer_amount,
+        )?;
+
+        let swap_msg = create_osmosis_swap_msg(
+            env.contract.address.to_string(),
+            coin(amount_to_swap.into(), route.token_in),
+            route.operations,
+            route.minimum_receive,
+        )?;
+        msgs.push(swap_msg);
+    }
+
+    msgs.push(
+        wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::AddToPosition {
+                position_id,
+                token_0,
+                token_1,
+                token_min_amount_0,
+                token_min_amount_1,
+                nonce,
+            },
+            vec![],
+        )?
+        .into(),
+    );
+
+    PENDING_ADD_TO_POSITIONS.save(
+        deps.storage,
+        nonce,
+        &PendingAddToPosition {
+            receiver: info.sender,
+            position_id,
+        },
+    )?;
+    Ok(Response::new().add_messages(msgs))
+}
+
+// Collects the rewards of a position and swaps them into target_denom, the withdraw
+// reply takes care of the swaps, the fee and the refund of the harvest
+#[allow(clippy::too_many_arguments)]
+pub fn harvest_rewards(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    target_denom: String,
+    paths: Vec<Vec<SwapOperation>>,
+    minimum_receive: Option<Uint128>,
+    deadline: Option<Expiration>,
+    ibc_return: Option<IbcReturn>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    validate_target_paths(&paths, &target_denom)?;
+    validate_ibc_return(ibc_return.as_ref())?;
+
+    let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let position = position_detail
+        .position
+        .as_ref()
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let rewards = claimable_rewards(&position_detail)?;
+
+    // rewards can only be collected by the contract once the position is escrowed,
+    // a position pulled for the harvest is handed back in the reply
+    let escrow_msg = escrow_position(deps.storage, &env, &info.sender, position)?;
+    let pulled_position_id = escrow_msg.as_ref().map(|_| position_id);
+
+    // snapshot rewards & target
+    let nonce = next_nonce(deps.storage)?;
+    for denom in rewards
+        .iter()
+        .map(|reward| &reward.denom)
+        .chain([&target_denom])
+    {
+        if !SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, denom)?;
+        }
+    }
+
+    let minimum_receive = minimum_receive
+        .map(|amount| vec![coin(amount.u128(), &target_denom)])
+        .unwrap_or_default();
+
+    PENDING_ZAP_OUTS.save(
+        deps.storage,
+        nonce,
+        &PendingZapOut {
+            receiver: info.sender,
+            pool_id: position.pool_id,
+            routes: vec![],
+            target: Some(ZapOutTarget {
+                denom: target_denom,
+                paths,
+            }),
+            minimum_receive,
+            position_id: pulled_position_id,
+            residual_position_id: pulled_position_id,
+            ibc_return,
+            referral: None,
+        },
+    )?;
+
+    let collect_spread_rewards_msg = MsgCollectSpreadRewards {
+        position_ids: vec![position_id],
+        sender: env.contract.address.to_string(),
+    };
+    // the reply follows the last collect message
+    let collect_incentives_msg = MsgCollectIncentives {
+        position_ids: vec![position_id],
+        sender: env.contract.address.to_string(),
+    };
+
+    Ok(Response::new()
+        .add_messages(escrow_msg)
+        .add_message(collect_spread_rewards_msg)
+        .add_submessage(SubMsg::reply_on_success(
+            collect_incentives_msg,
+            encode_reply_id(HARVEST_REWARDS_ID, nonce),
+        )))
+}
+
+// Moves a position to a new tick range: the position is fully withdrawn, routes swap
+// between its tokens in the reply, then a new position is created from the balances
+#[allow(clippy::too_many_arguments)]
+pub fn migrate_position(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    position_id: u64,
+    new_lower_tick: i64,
+    new_upper_tick: i64,
+    paths: Vec<Vec<SwapOperation>>,
+    max_slippage: Decimal,
+    token_min_amount_0: Option<Uint128>,
+    token_min_amount_1: Option<Uint128>,
+    deadline: Option<Expiration>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    if max_slippage > Decimal::one() {
+        return Err(ContractError::InvalidMaxSlippage);
+    }
+
+    let position_detail = ConcentratedliquidityQuerier::new(&deps.querier)
+        .position_by_id(position_id)?
+        .position
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let position = position_detail
+        .position
+        .as_ref()
+        .ok_or_else(|| StdError::not_found("position"))?;
+    let pool = query_concentrated_pool(&deps.as_ref(), position.pool_id)?;
+    let (token_0, token_1) = (pool.token0, pool.token1);
+
+    // paths only rebalance between the position tokens
+    for path in &paths {
+        let (first_op, last_op) = path
+            .first()
+            .zip(path.last())
+            .ok_or(ContractError::SwapOperationsEmpty)?;
+        let valid = (first_op.denom_in == token_0 && last_op.denom_out == token_1)
+            || (first_op.denom_in == token_1 && last_op.denom_out == token_0);
+        if !valid {
+            return Err(ContractError::InvalidMigrationRoutes);
+        }
+    }
+
+    // only the depositor of the position can migrate it
+    let escrow_msg = escrow_position(deps.storage, &env, &info.sender, position)?;
+
+    // snapshot position tokens & claimed rewards
+    let nonce = next_nonce(deps.storage)?;
+    for denom in [&token_0, &token_1].into_iter().chain(
+        position_detail
+            .claimable_spread_rewards
+            .iter()
+            .chain(position_detail.claimable_incentives.iter())
+            .map(|reward| &reward.denom),
+    ) {
+        if !SNAP_BALANCES.has(deps.storage, (nonce, denom)) {
+            snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, denom)?;
+        }
+    }
+
+    PENDING_MIGRATIONS.save(
+        deps.storage,
+        nonce,
+        &PendingMigration {
+            receiver: info.sender,
+            position_id,
+            pool_id: position.pool_id,
+            token_0,
+            token_1,
+            lower_tick: new_lower_tick,
+            upper_tick: new_upper_tick,
+            token_min_amount_0,
+            token_min_amount_1,
+            paths,
+            max_slippage,
         },
     )?;
 
@@ -254,8 +1269,79 @@ pub fn zap_out_liquidity(
         liquidity_amount,
     };
 
+    Ok(Response::new()
+        .add_messages(escrow_msg)
+        .add_submessage(SubMsg::reply_on_success(
+            withdraw_position_msg,
+            encode_reply_id(MIGRATE_POSITION_ID, nonce),
+        )))
+}
+
+// LP shares must be sent as funds
+#[allow(clippy::too_many_arguments)]
+pub fn zap_out_gamm_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    routes: Vec<Route>,
+    minimum_receive: Option<Vec<Coin>>,
+    deadline: Option<Expiration>,
+    ibc_return: Option<IbcReturn>,
+    referral: Option<Referral>,
+) -> ContractResult<Response> {
+    assert_deadline(&env, deadline)?;
+    let referral = validate_referral(deps.api, deps.storage, referral)?;
+    validate_ibc_return(ibc_return.as_ref())?;
+
+    let shares = one_coin(&info)?;
+    let pool_id = parse_gamm_share_denom(&shares.denom)?;
+    let denoms = query_gamm_pool_denoms(&deps.as_ref(), pool_id)?;
+
+    // snapshot pool tokens
+    let nonce = next_nonce(deps.storage)?;
+    for denom in &denoms {
+        snapshot_balances(deps.api, &deps.querier, deps.storage, &env, nonce, denom)?;
+    }
+
+    // snapshot token out of zap out
+    for route in &routes {
+        if let Some(ops) = route.operations.last() {
+            snapshot_balances(
+                deps.api,
+                &deps.querier,
+                deps.storage,
+                &env,
+                nonce,
+                &ops.denom_out,
+            )?;
+        }
+    }
+
+    PENDING_ZAP_OUTS.save(
+        deps.storage,
+        nonce,
+        &PendingZapOut {
+            receiver: info.sender,
+            pool_id,
+            routes,
+            target: None,
+            minimum_receive: minimum_receive.unwrap_or_default(),
+            position_id: None,
+            residual_position_id: None,
+            ibc_return,
+            referral,
+        },
+    )?;
+
+    let exit_pool_msg = MsgExitPool {
+        sender: env.contract.address.to_string(),
+        pool_id,
+        share_in_amount: shares.amount.to_string(),
+        token_out_mins: vec![],
+    };
+
     Ok(Response::new().add_submessage(SubMsg::reply_on_success(
-        withdraw_position_msg,
-        WITHDRAW_POSITION_ID,
+        exit_pool_msg,
+        encode_reply_id(EXIT_POOL_ID, nonce),
     )))
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::{OverflowError, StdError};
+use cosmwasm_std::{Decimal256RangeExceeded, DecimalRangeExceeded, OverflowError, StdError};
 use thiserror::Error;
 
 #[derive(Error, Debug, PartialEq)]
@@ -19,6 +19,12 @@ pub enum ZapperError {
     #[error(transparent)]
     Overflow(#[from] OverflowError),
 
+    #[error(transparent)]
+    DecimalRangeExceeded(#[from] DecimalRangeExceeded),
+
+    #[error(transparent)]
+    Decimal256RangeExceeded(#[from] Decimal256RangeExceeded),
+
     ////////////
     /// SWAP ///
     ////////////
@@ -35,6 +41,16 @@ pub enum ZapperError {
     #[error("Routes Empty")]
     RoutesEmpty,
 
+    //////////
+    /// CL ///
+    //////////
+
+    #[error("Tick {0} is out of range")]
+    InvalidTick(i64),
+
+    #[error("Lower tick must be less than upper tick")]
+    InvalidTickRange {},
+
     ///////////
     /// IBC ///
     ///////////
@@ -2,3 +2,4 @@ pub mod asset;
 pub mod error;
 pub mod proto_coin;
 pub mod swap;
+pub mod tick_math;
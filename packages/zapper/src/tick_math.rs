@@ -0,0 +1,324 @@
+use std::str::FromStr;
+
+use cosmwasm_std::{Decimal, Decimal256, StdError, Uint128, Uint256};
+
+use crate::error::ZapperError;
+
+// Osmosis concentrated liquidity tick constants. Every 9 * 10^6 ticks the
+// price moves by one order of magnitude, starting at 10^-6 per tick around
+// price one.
+pub const EXPONENT_AT_PRICE_ONE: i64 = -6;
+pub const GEOMETRIC_EXPONENT_INCREMENT_DISTANCE_IN_TICKS: i64 = 9_000_000;
+pub const MIN_TICK: i64 = -108_000_000;
+pub const MAX_TICK: i64 = 342_000_000;
+
+// Returns 10^exponent as a Decimal256, truncating anything below 1e-18.
+fn pow_ten(exponent: i64) -> Result<Decimal256, ZapperError> {
+    if exponent >= 0 {
+        let atomics = Uint256::from(10u128).checked_pow(exponent as u32)?;
+        Ok(Decimal256::from_atomics(atomics, 0)?)
+    } else {
+        Ok(Decimal256::from_atomics(
+            1u128,
+            exponent.unsigned_abs() as u32,
+        )?)
+    }
+}
+
+// Converts a tick index to its price, following the osmosis x/concentrated-liquidity
+// TickToPrice implementation.
+pub fn tick_to_price(tick: i64) -> Result<Decimal256, ZapperError> {
+    if !(MIN_TICK..=MAX_TICK).contains(&tick) {
+        return Err(ZapperError::InvalidTick(tick));
+    }
+    if tick == 0 {
+        return Ok(Decimal256::one());
+    }
+
+    let geometric_exponent_delta = tick / GEOMETRIC_EXPONENT_INCREMENT_DISTANCE_IN_TICKS;
+    let mut exponent_at_current_tick = EXPONENT_AT_PRICE_ONE + geometric_exponent_delta;
+    if tick < 0 {
+        exponent_at_current_tick -= 1;
+    }
+
+    let current_additive_increment_in_ticks = pow_ten(exponent_at_current_tick)?;
+    let num_additive_ticks =
+        tick - geometric_exponent_delta * GEOMETRIC_EXPONENT_INCREMENT_DISTANCE_IN_TICKS;
+    let additive = current_additive_increment_in_ticks.checked_mul(Decimal256::from_atomics(
+        num_additive_ticks.unsigned_abs(),
+        0,
+    )?)?;
+
+    let base = pow_ten(geometric_exponent_delta)?;
+    if num_additive_ticks < 0 {
+        Ok(base.checked_sub(additive)?)
+    } else {
+        Ok(base.checked_add(additive)?)
+    }
+}
+
+pub fn tick_to_sqrt_price(tick: i64) -> Result<Decimal256, ZapperError> {
+    Ok(tick_to_price(tick)?.sqrt())
+}
+
+// Parses an osmosis decimal string (Dec or BigDec, up to 36 fractional digits)
+// into a Decimal256, truncating the fractional part to 18 digits.
+pub fn parse_osmosis_decimal(value: &str) -> Result<Decimal256, ZapperError> {
+    let truncated = match value.split_once('.') {
+        Some((whole, fractional)) if fractional.len() > 18 => {
+            format!("{}.{}", whole, &fractional[..18])
+        }
+        _ => value.to_string(),
+    };
+    Ok(Decimal256::from_str(&truncated)?)
+}
+
+// Returns the share of the input value that should be held as token 0 so that
+// a position in [lower_sqrt_price, upper_sqrt_price] can be created at
+// current_sqrt_price without leftovers, assuming swaps execute at the pool price.
+//
+// Inside the range the position holds
+//     amount_0 = L * (sb - s) / (s * sb)
+//     amount_1 = L * (s - sa)
+// and amount_1 is worth amount_1 / s^2 of token 0, which gives
+//     share_0 = s * (sb - s) / (s * (sb - s) + sb * (s - sa))
+pub fn token_0_share(
+    current_sqrt_price: Decimal256,
+    lower_sqrt_price: Decimal256,
+    upper_sqrt_price: Decimal256,
+) -> Result<Decimal, ZapperError> {
+    if lower_sqrt_price >= upper_sqrt_price {
+        return Err(ZapperError::InvalidTickRange {});
+    }
+    if current_sqrt_price <= lower_sqrt_price {
+        return Ok(Decimal::one());
+    }
+    if current_sqrt_price >= upper_sqrt_price {
+        return Ok(Decimal::zero());
+    }
+
+    let value_0 = current_sqrt_price.checked_mul(upper_sqrt_price - current_sqrt_price)?;
+    let value_1 = upper_sqrt_price.checked_mul(current_sqrt_price - lower_sqrt_price)?;
+    let share_0 =
+        Decimal256::checked_from_ratio(value_0.atomics(), value_0.checked_add(value_1)?.atomics())
+            .map_err(|_| ZapperError::InvalidTickRange {})?;
+
+    Ok(Decimal::try_from(share_0)?)
+}
+
+// Liquidity obtained when providing amount_0 and amount_1 to the range, following
+// the osmosis GetLiquidityFromAmounts implementation.
+pub fn liquidity_for_amounts(
+    current_sqrt_price: Decimal256,
+    lower_sqrt_price: Decimal256,
+    upper_sqrt_price: Decimal256,
+    amount_0: Uint128,
+    amount_1: Uint128,
+) -> Result<Decimal256, ZapperError> {
+    if lower_sqrt_price >= upper_sqrt_price {
+        return Err(ZapperError::InvalidTickRange {});
+    }
+    let amount_0 = Decimal256::from_atomics(amount_0, 0)?;
+    let amount_1 = Decimal256::from_atomics(amount_1, 0)?;
+
+    // L = amount_0 * sa * sb / (sb - sa)
+    let liquidity_0 = |sa: Decimal256, sb: Decimal256| -> Result<Decimal256, ZapperError> {
+        Ok(amount_0.checked_mul(sa)?.checked_mul(sb)? / (sb - sa))
+    };
+    // L = amount_1 / (sb - sa)
+    let liquidity_1 = |sa: Decimal256, sb: Decimal256| amount_1 / (sb - sa);
+
+    if current_sqrt_price <= lower_sqrt_price {
+        liquidity_0(lower_sqrt_price, upper_sqrt_price)
+    } else if current_sqrt_price >= upper_sqrt_price {
+        Ok(liquidity_1(lower_sqrt_price, upper_sqrt_price))
+    } else {
+        Ok(std::cmp::min(
+            liquidity_0(current_sqrt_price, upper_sqrt_price)?,
+            liquidity_1(lower_sqrt_price, current_sqrt_price),
+        ))
+    }
+}
+
+// Token amounts required to add liquidity to the range, rounded up as osmosis does
+// when a position is created.
+pub fn amounts_for_liquidity(
+    current_sqrt_price: Decimal256,
+    lower_sqrt_price: Decimal256,
+    upper_sqrt_price: Decimal256,
+    liquidity: Decimal256,
+) -> Result<(Uint128, Uint128), ZapperError> {
+    if lower_sqrt_price >= upper_sqrt_price {
+        return Err(ZapperError::InvalidTickRange {});
+    }
+    let sqrt_price = current_sqrt_price.clamp(lower_sqrt_price, upper_sqrt_price);
+
+    // amount_0 = L * (sb - s) / (s * sb)
+    let amount_0 = liquidity.checked_mul(upper_sqrt_price - sqrt_price)?
+        / sqrt_price.checked_mul(upper_sqrt_price)?;
+    // amount_1 = L * (s - sa)
+    let amount_1 = liquidity.checked_mul(sqrt_price - lower_sqrt_price)?;
+
+    Ok((
+        Uint128::try_from(amount_0.to_uint_ceil()).map_err(StdError::from)?,
+        Uint128::try_from(amount_1.to_uint_ceil()).map_err(StdError::from)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_to_price() {
+        assert_eq!(tick_to_price(0).unwrap(), Decimal256::one());
+        assert_eq!(
+            tick_to_price(1).unwrap(),
+            Decimal256::from_str("1.000001").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(-1).unwrap(),
+            Decimal256::from_str("0.9999999").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(9_000_000).unwrap(),
+            Decimal256::from_str("10").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(9_000_001).unwrap(),
+            Decimal256::from_str("10.00001").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(-9_000_000).unwrap(),
+            Decimal256::from_str("0.1").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(-4_500_000).unwrap(),
+            Decimal256::from_str("0.55").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(MIN_TICK).unwrap(),
+            Decimal256::from_str("0.000000000001").unwrap()
+        );
+        assert_eq!(
+            tick_to_price(MAX_TICK).unwrap(),
+            Decimal256::from_str("100000000000000000000000000000000000000").unwrap()
+        );
+
+        assert_eq!(
+            tick_to_price(MAX_TICK + 1),
+            Err(ZapperError::InvalidTick(MAX_TICK + 1))
+        );
+        assert_eq!(
+            tick_to_price(MIN_TICK - 1),
+            Err(ZapperError::InvalidTick(MIN_TICK - 1))
+        );
+    }
+
+    #[test]
+    fn test_parse_osmosis_decimal() {
+        assert_eq!(
+            parse_osmosis_decimal("1.000000000000000000000000000000000000").unwrap(),
+            Decimal256::one()
+        );
+        assert_eq!(
+            parse_osmosis_decimal("0.123456789012345678999999999999999999").unwrap(),
+            Decimal256::from_str("0.123456789012345678").unwrap()
+        );
+        assert_eq!(
+            parse_osmosis_decimal("42").unwrap(),
+            Decimal256::from_str("42").unwrap()
+        );
+        assert!(parse_osmosis_decimal("abc").is_err());
+    }
+
+    #[test]
+    fn test_token_0_share() {
+        let lower = tick_to_sqrt_price(-9_000_000).unwrap();
+        let upper = tick_to_sqrt_price(9_000_000).unwrap();
+
+        // TEST 1: current price below the range, only token 0 is needed
+        let share = token_0_share(tick_to_sqrt_price(-18_000_000).unwrap(), lower, upper).unwrap();
+        assert_eq!(share, Decimal::one());
+
+        // TEST 2: current price above the range, only token 1 is needed
+        let share = token_0_share(tick_to_sqrt_price(18_000_000).unwrap(), lower, upper).unwrap();
+        assert_eq!(share, Decimal::zero());
+
+        // TEST 3: range symmetric around price one, value is split in half
+        let share = token_0_share(Decimal256::one(), lower, upper).unwrap();
+        assert_eq!(share, Decimal::percent(50));
+
+        // TEST 4: price close to the upper bound, mostly token 1 is needed
+        let share = token_0_share(tick_to_sqrt_price(8_000_000).unwrap(), lower, upper).unwrap();
+        assert!(share < Decimal::percent(10));
+
+        // TEST 5: invalid range
+        assert_eq!(
+            token_0_share(Decimal256::one(), upper, lower),
+            Err(ZapperError::InvalidTickRange {})
+        );
+    }
+
+    #[test]
+    fn test_liquidity_for_amounts() {
+        let lower = tick_to_sqrt_price(-9_000_000).unwrap();
+        let upper = tick_to_sqrt_price(9_000_000).unwrap();
+
+        // TEST 1: price in range, balanced amounts are fully used
+        let liquidity = liquidity_for_amounts(
+            Decimal256::one(),
+            lower,
+            upper,
+            Uint128::new(1_000_000),
+            Uint128::new(1_000_000),
+        )
+        .unwrap();
+        let (amount_0, amount_1) =
+            amounts_for_liquidity(Decimal256::one(), lower, upper, liquidity).unwrap();
+        assert!(amount_0.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+        assert!(amount_1.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+
+        // TEST 2: price in range, the excess of token 1 is left over
+        let liquidity = liquidity_for_amounts(
+            Decimal256::one(),
+            lower,
+            upper,
+            Uint128::new(1_000_000),
+            Uint128::new(3_000_000),
+        )
+        .unwrap();
+        let (amount_0, amount_1) =
+            amounts_for_liquidity(Decimal256::one(), lower, upper, liquidity).unwrap();
+        assert!(amount_0.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+        assert!(amount_1.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+
+        // TEST 3: price below the range, only token 0 is used
+        let current = tick_to_sqrt_price(-18_000_000).unwrap();
+        let liquidity = liquidity_for_amounts(
+            current,
+            lower,
+            upper,
+            Uint128::new(1_000_000),
+            Uint128::new(1_000_000),
+        )
+        .unwrap();
+        let (amount_0, amount_1) = amounts_for_liquidity(current, lower, upper, liquidity).unwrap();
+        assert!(amount_0.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+        assert_eq!(amount_1, Uint128::zero());
+
+        // TEST 4: price above the range, only token 1 is used
+        let current = tick_to_sqrt_price(18_000_000).unwrap();
+        let liquidity = liquidity_for_amounts(
+            current,
+            lower,
+            upper,
+            Uint128::new(1_000_000),
+            Uint128::new(1_000_000),
+        )
+        .unwrap();
+        let (amount_0, amount_1) = amounts_for_liquidity(current, lower, upper, liquidity).unwrap();
+        assert_eq!(amount_0, Uint128::zero());
+        assert!(amount_1.abs_diff(Uint128::new(1_000_000)) <= Uint128::one());
+    }
+}